@@ -2,10 +2,16 @@ extern crate config;
 extern crate serde_json;
 
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use tokio::{sync::Mutex, runtime};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::signal::unix::{signal, SignalKind};
 use listeria::listeria_bot::ListeriaBot;
 use tokio::time::{sleep, Duration};
 use std::env;
+use tracing::{debug, error, info, warn};
+use listeria::logging::init_logging;
 
 /*
 TEST DB CONNECT
@@ -23,35 +29,163 @@ toolforge-jobs run --image tf-php74 --mem 2500Mi --continuous --command '/data/p
 */
 
 const DEFAULT_THREADS: usize = 4;
+/// Pause before trying the next page, if another instance claimed the last one
+const CLAIM_CONFLICT_PAUSE_MS: u64 = 100;
+/// Pause before trying the next page, if getting one failed otherwise (eg no pages, database problems)
+const ERROR_PAUSE_MS: u64 = 5000;
+
+/// Waits for SIGTERM (sent by toolforge-jobs on delete) or Ctrl-C
+async fn wait_for_shutdown_signal() {
+    let mut sigterm = match signal(SignalKind::terminate()) {
+        Ok(sigterm) => sigterm,
+        Err(e) => {
+            error!("Cannot install SIGTERM handler: {}", e);
+            let _ = tokio::signal::ctrl_c().await;
+            return;
+        }
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = sigterm.recv() => {}
+    }
+}
+
+/// Renews the leases of pages running on this instance, and reclaims expired leases of other instances
+fn spawn_heartbeat(bot: Arc<ListeriaBot>) {
+    let interval = Duration::from_secs((bot.config().running_lease_seconds() / 3).max(1));
+    tokio::spawn(async move {
+        loop {
+            sleep(interval).await;
+            if let Err(e) = bot.heartbeat().await {
+                error!("{}", &e);
+            }
+            if let Err(e) = bot.reclaim_stale_running().await {
+                error!("{}", &e);
+            }
+            if let Err(e) = bot.update_queue_depth().await {
+                error!("{}", &e);
+            }
+        }
+    });
+}
+
+/// Serves `/metrics` (Prometheus text format) and `/health`
+fn spawn_metrics_server(bot: Arc<ListeriaBot>, shutdown: Arc<AtomicBool>, address: String) {
+    tokio::spawn(async move {
+        let listener = match TcpListener::bind(&address).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("Cannot start metrics server on {}: {}", &address, e);
+                return;
+            }
+        };
+        info!("Metrics server listening on {}", &address);
+        loop {
+            let mut socket = match listener.accept().await {
+                Ok((socket, _)) => socket,
+                Err(_) => continue,
+            };
+            let metrics = bot.metrics();
+            let shutdown = shutdown.clone();
+            tokio::spawn(async move {
+                let mut buffer = [0; 1024];
+                let n = match socket.read(&mut buffer).await {
+                    Ok(n) => n,
+                    Err(_) => return,
+                };
+                let request = String::from_utf8_lossy(&buffer[..n]);
+                let path = request.split_whitespace().nth(1).unwrap_or("/");
+                let (status, body) = match path {
+                    "/metrics" => ("200 OK", metrics.render()),
+                    "/health" => match shutdown.load(Ordering::SeqCst) {
+                        true => ("503 Service Unavailable", "SHUTTING DOWN\n".to_string()),
+                        false => ("200 OK", "OK\n".to_string()),
+                    },
+                    _ => ("404 Not Found", "Not found\n".to_string()),
+                };
+                let response = format!(
+                    "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                let _ = socket.write_all(response.as_bytes()).await;
+            });
+        }
+    });
+}
 
 async fn run_singles(threads: usize) {
     let running_counter = Arc::new(Mutex::new(0 as usize));
+    let shutdown = Arc::new(AtomicBool::new(false));
     let bot = ListeriaBot::new("config.json").await.unwrap();
-    let _ = bot.reset_running().await;
+    let _ = bot.reclaim_stale_running().await;
     let bot = Arc::new(bot);
-    loop {
-        while *running_counter.lock().await>=threads {
+    spawn_heartbeat(bot.clone());
+    if let Some(address) = bot.config().metrics_address() {
+        spawn_metrics_server(bot.clone(), shutdown.clone(), address.to_owned());
+    }
+
+    let shutdown_clone = shutdown.clone();
+    tokio::spawn(async move {
+        wait_for_shutdown_signal().await;
+        info!("Shutdown requested, waiting for running pages to finish");
+        shutdown_clone.store(true, Ordering::SeqCst);
+    });
+
+    while !shutdown.load(Ordering::SeqCst) {
+        if *running_counter.lock().await>=threads {
             sleep(Duration::from_millis(100)).await;
+            continue;
         }
         let page = match bot.prepare_next_single_page().await {
             Ok(page) => page,
-            Err(_) => continue,
+            Err(e) if ListeriaBot::is_claim_conflict(&e) => {
+                debug!("{}", &e);
+                sleep(Duration::from_millis(CLAIM_CONFLICT_PAUSE_MS)).await;
+                continue;
+            }
+            Err(e) => {
+                warn!("{}", &e);
+                sleep(Duration::from_millis(ERROR_PAUSE_MS)).await;
+                continue;
+            }
         };
         let bot = bot.clone();
         let running_counter = running_counter.clone();
-        *running_counter.lock().await += 1 ;
+        {
+            let mut counter = running_counter.lock().await;
+            *counter += 1;
+            bot.metrics().set_running(*counter);
+        }
         tokio::spawn(async move {
             // println!("Running: {} for {:?}",running_counter.lock().await,&page);
-            if let Err(e) = bot.run_single_bot(page).await {
-                println!("{}", &e)
+            // Run the page in its own task, so the counter goes down even if it panics
+            let page_bot = bot.clone();
+            match tokio::spawn(async move { page_bot.run_single_bot(page).await }).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => error!("{}", &e),
+                Err(e) => error!("Page task failed: {}", &e),
             }
-            *running_counter.lock().await -= 1 ;
+            let mut counter = running_counter.lock().await;
+            *counter -= 1;
+            bot.metrics().set_running(*counter);
         });
     }
+
+    // Stop taking new pages, wait for the running ones
+    while *running_counter.lock().await>0 {
+        sleep(Duration::from_millis(100)).await;
+    }
+    if let Err(e) = bot.release_running().await {
+        error!("{}", &e);
+    }
+    info!("Shutdown complete");
 }
 
 //#[tokio::main(flavor = "multi_thread", worker_threads = 4)]
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    init_logging("info");
     let argv: Vec<_> = env::args_os().collect();
     let threads = match argv.get(1) {
         Some(t) => t.to_owned().into_string().unwrap_or("".into()).parse::<usize>().unwrap_or(DEFAULT_THREADS),
@@ -3,10 +3,12 @@ extern crate serde_json;
 
 use config::{Config, File};
 use listeria::configuration::Configuration;
+use listeria::listeria_bot::ListeriaBot;
 use listeria::listeria_page::ListeriaPage;
 use std::env;
 use std::sync::Arc;
 use tokio::sync::RwLock;
+use listeria::logging::init_logging;
 
 async fn update_page(settings: &Config, page_title: &str, api_url: &str) -> Result<String, String> {
     let config = Arc::new(Configuration::new_from_file("config.json").await.unwrap());
@@ -22,16 +24,67 @@ async fn update_page(settings: &Config, page_title: &str, api_url: &str) -> Resu
     let mut page = ListeriaPage::new(config, mw_api, page_title.into()).await?;
     page.run().await?;
 
-    let message = match page.update_source_page().await? {
+    let mut message = match page.update_source_page().await? {
         true => format!("{} edited", &page_title),
         false => format!("{} not edited", &page_title),
     };
+    for warning in page.warnings() {
+        message += &format!("\nWARNING: {}", warning);
+    }
 
     Ok(message)
 }
 
+/// Checks the list definitions on a page, without running any queries
+async fn lint_page(page_title: &str, api_url: &str) -> Result<String, String> {
+    let config = Arc::new(Configuration::new_from_file("config.json").await?);
+    let mw_api = wikibase::mediawiki::api::Api::new(api_url)
+        .await
+        .map_err(|e| e.to_string())?;
+    let mw_api = Arc::new(RwLock::new(mw_api));
+    let page = ListeriaPage::new(config, mw_api, page_title.into()).await?;
+    let issues = page.lint().await?;
+    if issues.is_empty() {
+        return Ok(format!("{}: no problems found", &page_title));
+    }
+    let issues: Vec<String> = issues.iter().map(|i| i.to_string()).collect();
+    Ok(format!("{}:\n{}", &page_title, issues.join("\n")))
+}
+
+/// Checks the list definitions on all pages in the bot database
+async fn audit_pages(wiki: Option<&String>) -> Result<String, String> {
+    let bot = ListeriaBot::new("config.json").await?;
+    let mut ret = vec![];
+    for (wiki, page, issues) in bot.lint_pages(wiki.map(|s| s.as_str())).await? {
+        ret.push(format!("{}\t{}", wiki, page));
+        for issue in issues {
+            ret.push(format!("\t{}", issue));
+        }
+    }
+    Ok(ret.join("\n"))
+}
+
 #[tokio::main]
 async fn main() -> Result<(), String> {
+    init_logging("warn");
+    let args: Vec<String> = env::args().collect();
+    match args.get(1).map(|s| s.as_str()) {
+        Some("lint") => {
+            // main lint WIKI_SERVER PAGE
+            let wiki_server = args.get(2).ok_or("No wiki server argument")?;
+            let page = args.get(3).ok_or("No page argument")?;
+            let wiki_api = format!("https://{}/w/api.php", &wiki_server);
+            println!("{}", lint_page(page, &wiki_api).await?);
+            return Ok(());
+        }
+        Some("audit") => {
+            // main audit [WIKI]
+            println!("{}", audit_pages(args.get(2)).await?);
+            return Ok(());
+        }
+        _ => {}
+    }
+
     let ini_file = "listeria.ini";
 
     let settings = Config::builder()
@@ -39,7 +92,6 @@ async fn main() -> Result<(), String> {
         .build()
         .unwrap_or_else(|_| panic!("INI file '{}' can't be opened", ini_file));
 
-    let args: Vec<String> = env::args().collect();
     let wiki_server = args
         .get(1)
         .ok_or_else(|| "No wiki server argument".to_string())?;
@@ -1,5 +1,16 @@
+use crate::entity_cache::EntityCache;
+use crate::messages::Messages;
+use crate::sparql::SparqlEndpoint;
+use crate::sparql_cache::SparqlCache;
 use crate::*;
+use chrono::Utc;
+use lru::LruCache;
+use std::num::NonZeroUsize;
 use std::path::Path;
+use std::sync::Mutex;
+
+const REGION_CACHE_MAX_ENTRIES: usize = 100_000;
+const REGION_CACHE_TTL: i64 = 86400;
 
 #[derive(Debug, Clone)]
 pub enum NamespaceGroup {
@@ -16,9 +27,205 @@ impl NamespaceGroup {
     }
 }
 
+/// Per-Wikibase settings, for lists that use an instance other than Wikidata
+#[derive(Debug, Clone)]
+pub struct WikibaseSettings {
+    concept_uri: Option<String>,
+    interwiki_prefix: String,
+    home_wiki: String,
+    properties: HashMap<String, String>, // Wikidata property => local property
+}
+
+impl Default for WikibaseSettings {
+    fn default() -> Self {
+        Self {
+            concept_uri: None,
+            interwiki_prefix: ":d:".to_string(),
+            home_wiki: "wikidatawiki".to_string(),
+            properties: HashMap::new(),
+        }
+    }
+}
+
+impl WikibaseSettings {
+    /// Reads eg `{"concept_uri":"https://example.wikibase.cloud/entity/","interwiki_prefix":":wb:","home_wiki":"examplewiki","properties":{"P131":"P12","P300":"P45"}}`
+    pub fn new_from_json(j: &Value) -> Self {
+        let mut ret = Self::default();
+        if let Some(s) = j["concept_uri"].as_str() {
+            ret.concept_uri = Some(s.to_string());
+        }
+        if let Some(s) = j["interwiki_prefix"].as_str() {
+            ret.interwiki_prefix = s.to_string();
+        }
+        if let Some(s) = j["home_wiki"].as_str() {
+            ret.home_wiki = s.to_string();
+        }
+        if let Some(o) = j["properties"].as_object() {
+            for (k, v) in o.iter() {
+                if let Some(v) = v.as_str() {
+                    ret.properties.insert(k.to_uppercase(), v.to_uppercase());
+                }
+            }
+        }
+        ret
+    }
+
+    /// Entity URI prefix, if configured; otherwise, any `.../entity/ID` URI is treated as an entity
+    pub fn concept_uri(&self) -> Option<&str> {
+        self.concept_uri.as_deref()
+    }
+
+    /// Entity URI prefix for queries, eg `http://www.wikidata.org/entity/`
+    pub fn entity_uri(&self) -> &str {
+        self.concept_uri
+            .as_deref()
+            .unwrap_or("http://www.wikidata.org/entity/")
+    }
+
+    /// Direct-claim predicate prefix, eg `http://www.wikidata.org/prop/direct/`
+    pub fn direct_property_uri(&self) -> String {
+        let entity_uri = self.entity_uri();
+        match entity_uri.strip_suffix("entity/") {
+            Some(base) => format!("{}prop/direct/", base),
+            None => format!("{}prop/direct/", entity_uri),
+        }
+    }
+
+    /// Link prefix to the Wikibase from other wikis, eg `:d:`
+    pub fn interwiki_prefix(&self) -> &str {
+        &self.interwiki_prefix
+    }
+
+    /// The wiki the Wikibase lives on, eg `wikidatawiki`
+    pub fn home_wiki(&self) -> &str {
+        &self.home_wiki
+    }
+
+    /// Local equivalent of a Wikidata property
+    pub fn property(&self, wikidata_property: &str) -> String {
+        match self.properties.get(wikidata_property) {
+            Some(p) => p.to_owned(),
+            None => wikidata_property.to_string(),
+        }
+    }
+}
+
+/// A local citation template for references, and its parameter names
+#[derive(Debug, Clone, PartialEq)]
+pub struct CitationTemplate {
+    template: String,
+    params: HashMap<String, String>, // Reference field => local parameter name
+}
+
+impl CitationTemplate {
+    /// Reads eg `{"template":"Internetquelle","url":"url","title":"titel","website":"werk","access_date":"zugriff"}`.
+    /// Fields are url, title, author, date, website, pages, access_date, archive_url, archive_date, and imported_from.
+    pub fn new_from_json(j: &Value) -> Option<Self> {
+        let template = j["template"].as_str()?.trim().to_string();
+        if template.is_empty() {
+            return None;
+        }
+        let params = j
+            .as_object()?
+            .iter()
+            .filter(|(k, _v)| *k != "template")
+            .filter_map(|(k, v)| Some((k.to_string(), v.as_str()?.trim().to_string())))
+            .collect();
+        Some(Self { template, params })
+    }
+
+    /// `cite web`, or its local name
+    pub fn new_cite_web(template: &str) -> Self {
+        let params = vec![
+            ("url", "url"),
+            ("title", "title"),
+            ("author", "author"),
+            ("date", "date"),
+            ("website", "website"),
+            ("pages", "pages"),
+            ("access_date", "access-date"),
+            ("archive_url", "archive-url"),
+            ("archive_date", "archive-date"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        Self {
+            template: template.to_string(),
+            params,
+        }
+    }
+
+    pub fn template(&self) -> &str {
+        &self.template
+    }
+
+    /// Local parameter name for a reference field; None if the template has no such parameter
+    pub fn param(&self, field: &str) -> Option<&str> {
+        self.params
+            .get(field)
+            .map(|s| s.as_str())
+            .filter(|s| !s.is_empty())
+    }
+}
+
+/// ISO 3166-2 regions of entities, shared across lists and pages.
+/// Bounded by number of entries; entries expire after `ttl` seconds, so changes on Wikidata are picked up.
+#[derive(Debug)]
+pub struct RegionCache {
+    entries: Mutex<LruCache<String, (i64, Option<String>)>>, // Key => (timestamp, region)
+    ttl: i64,
+}
+
+impl Default for RegionCache {
+    fn default() -> Self {
+        Self::new(REGION_CACHE_MAX_ENTRIES, REGION_CACHE_TTL)
+    }
+}
+
+impl RegionCache {
+    pub fn new(max_entries: usize, ttl: i64) -> Self {
+        let max_entries = NonZeroUsize::new(max_entries.max(1)).expect("max_entries is at least 1");
+        Self {
+            entries: Mutex::new(LruCache::new(max_entries)),
+            ttl,
+        }
+    }
+
+    /// Reads eg `{"max_entries":100000,"ttl":86400}`
+    pub fn new_from_json(j: &Value) -> Self {
+        let max_entries = j["max_entries"]
+            .as_u64()
+            .map(|i| i as usize)
+            .unwrap_or(REGION_CACHE_MAX_ENTRIES);
+        let ttl = j["ttl"].as_i64().unwrap_or(REGION_CACHE_TTL);
+        Self::new(max_entries, ttl)
+    }
+
+    /// Returns Some(None) if the entity is known to have no region, None if the entity is not cached or expired
+    pub fn get(&self, key: &str) -> Option<Option<String>> {
+        let mut entries = self.entries.lock().ok()?;
+        let (timestamp, region) = entries.get(&key.to_string())?.to_owned();
+        if Utc::now().timestamp() - timestamp > self.ttl {
+            entries.pop(&key.to_string());
+            return None;
+        }
+        Some(region)
+    }
+
+    pub fn set(&self, key: &str, region: Option<String>) {
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.put(key.to_string(), (Utc::now().timestamp(), region));
+        }
+    }
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct Configuration {
     wb_apis: HashMap<String, Arc<Api>>,
+    sparql_endpoints: HashMap<String, SparqlEndpoint>,
+    default_sparql_endpoint: Option<SparqlEndpoint>,
+    wikibase_settings: HashMap<String, WikibaseSettings>,
     namespace_blocks: HashMap<String, NamespaceGroup>,
     default_api: String,
     prefer_preferred: bool,
@@ -26,11 +233,26 @@ pub struct Configuration {
     template_start_sites: HashMap<String, String>,
     template_end_sites: HashMap<String, String>,
     location_templates: HashMap<String, String>,
+    error_templates: HashMap<String, String>,
+    warning_templates: HashMap<String, String>,
+    tabbed_data_templates: HashMap<String, String>,
+    messages: Messages,
+    citation_templates: Option<HashMap<String, Option<CitationTemplate>>>, // None if not configured at all
     shadow_images_check: Vec<String>,
     default_thumbnail_size: Option<u64>,
+    max_rows_per_list: Option<u64>,
+    max_page_bytes: Option<u64>,
+    truncate_oversized_lists: bool,
+    suppress_imported_references: bool,
     location_regions: Vec<String>,
     mysql: Option<Value>,
     oauth2_token: String,
+    instance_id: Option<String>,
+    running_lease_seconds: Option<u64>,
+    metrics_address: Option<String>,
+    entity_cache: Option<Arc<EntityCache>>,
+    sparql_cache: Option<Arc<SparqlCache>>,
+    region_cache: Arc<RegionCache>,
 }
 
 impl Configuration {
@@ -56,6 +278,18 @@ impl Configuration {
         if let Some(i) = j["default_thumbnail_size"].as_u64() {
             ret.default_thumbnail_size = Some(i)
         }
+        if let Some(i) = j["max_rows_per_list"].as_u64() {
+            ret.max_rows_per_list = Some(i)
+        }
+        if let Some(i) = j["max_page_bytes"].as_u64() {
+            ret.max_page_bytes = Some(i)
+        }
+        if let Some(b) = j["truncate_oversized_lists"].as_bool() {
+            ret.truncate_oversized_lists = b
+        }
+        if let Some(b) = j["suppress_imported_references"].as_bool() {
+            ret.suppress_imported_references = b
+        }
         if let Some(sic) = j["shadow_images_check"].as_array() {
             ret.shadow_images_check = sic
                 .iter()
@@ -71,17 +305,53 @@ impl Configuration {
         if j["mysql"].is_object() {
             ret.mysql = Some(j["mysql"].to_owned());
         }
+        if let Some(s) = j["instance_id"].as_str() {
+            ret.instance_id = Some(s.to_string())
+        }
+        if let Some(i) = j["running_lease_seconds"].as_u64() {
+            ret.running_lease_seconds = Some(i)
+        }
+        if let Some(s) = j["metrics_address"].as_str() {
+            ret.metrics_address = Some(s.to_string())
+        }
+        if j["entity_cache"].is_object() {
+            ret.entity_cache = EntityCache::new_from_json(&j["entity_cache"])?.map(Arc::new);
+        }
+        if j["sparql_cache"].is_object() {
+            ret.sparql_cache = Some(Arc::new(SparqlCache::new_from_json(&j["sparql_cache"])?));
+        }
+        if j["region_cache"].is_object() {
+            ret.region_cache = Arc::new(RegionCache::new_from_json(&j["region_cache"]));
+        }
 
-        // valid WikiBase APIs
+        // valid WikiBase APIs; either just the API URL, or an object with "url" and optional "sparql" settings
         let oauth2_token = ret.oauth2_token.to_owned();
+        ret.default_sparql_endpoint = Some(
+            SparqlEndpoint::new_from_json(&j["sparql"], &oauth2_token)
+                .map_err(|e| format!("sparql: {}", e))?,
+        );
         if let Some(o) = j["apis"].as_object() {
             for (k, v) in o.iter() {
-                if let (name, Some(url)) = (k.as_str(), v.as_str()) {
-                    let mut api = wikibase::mediawiki::api::Api::new(&url)
-                        .await
-                        .map_err(|e| e.to_string())?;
-                    api.set_oauth2(&oauth2_token);
-                    ret.wb_apis.insert(name.to_string(), Arc::new(api));
+                let url = match v.as_str() {
+                    Some(url) => url,
+                    None => match v["url"].as_str() {
+                        Some(url) => url,
+                        None => continue,
+                    },
+                };
+                let mut api = wikibase::mediawiki::api::Api::new(&url)
+                    .await
+                    .map_err(|e| e.to_string())?;
+                api.set_oauth2(&oauth2_token);
+                ret.wb_apis.insert(k.to_string(), Arc::new(api));
+                if v.is_object() {
+                    ret.wikibase_settings
+                        .insert(k.to_string(), WikibaseSettings::new_from_json(v));
+                }
+                if v["sparql"].is_object() {
+                    let endpoint = SparqlEndpoint::new_from_json(&v["sparql"], &oauth2_token)
+                        .map_err(|e| format!("apis[{}]: {}", k, e))?;
+                    ret.sparql_endpoints.insert(k.to_string(), endpoint);
                 }
             }
         }
@@ -95,6 +365,43 @@ impl Configuration {
             }
         }
 
+        // Inline error/warning box patterns
+        if let Some(o) = j["error_templates"].as_object() {
+            for (k, v) in o.iter() {
+                if let (k, Some(v)) = (k.as_str(), v.as_str()) {
+                    ret.error_templates.insert(k.to_string(), v.to_string());
+                }
+            }
+        }
+        if let Some(o) = j["warning_templates"].as_object() {
+            for (k, v) in o.iter() {
+                if let (k, Some(v)) = (k.as_str(), v.as_str()) {
+                    ret.warning_templates.insert(k.to_string(), v.to_string());
+                }
+            }
+        }
+
+        // Bot-generated text on pages, by language; added to the bundled messages
+        ret.messages = Messages::new_from_json(&j["messages"], &ret.default_language);
+
+        // Citation templates for references; `null` for plain links
+        if let Some(o) = j["citation_templates"].as_object() {
+            let citation_templates = o
+                .iter()
+                .map(|(k, v)| (k.to_string(), CitationTemplate::new_from_json(v)))
+                .collect();
+            ret.citation_templates = Some(citation_templates);
+        }
+
+        // Local template/module calls showing a tabbed data page
+        if let Some(o) = j["tabbed_data_templates"].as_object() {
+            for (k, v) in o.iter() {
+                if let (k, Some(v)) = (k.as_str(), v.as_str()) {
+                    ret.tabbed_data_templates.insert(k.to_string(), v.to_string());
+                }
+            }
+        }
+
         // Namespace blocks on wikis
         if let Some(o) = j["namespace_blocks"].as_object() {
             for (k, v) in o.iter() {
@@ -150,6 +457,28 @@ impl Configuration {
         &self.oauth2_token
     }
 
+    pub fn instance_id(&self) -> &Option<String> {
+        &self.instance_id
+    }
+
+    /// Seconds after which a RUNNING page without heartbeat may be reclaimed by another bot instance
+    pub fn running_lease_seconds(&self) -> u64 {
+        self.running_lease_seconds.unwrap_or(600)
+    }
+
+    /// Address for the bot's metrics/health HTTP listener, eg "0.0.0.0:8000"
+    pub fn metrics_address(&self) -> &Option<String> {
+        &self.metrics_address
+    }
+
+    pub fn entity_cache(&self) -> Option<Arc<EntityCache>> {
+        self.entity_cache.clone()
+    }
+
+    pub fn sparql_cache(&self) -> &Option<Arc<SparqlCache>> {
+        &self.sparql_cache
+    }
+
     pub fn mysql(&self, key: &str) -> Value {
         match &self.mysql {
             Some(mysql) => mysql[key].to_owned(),
@@ -216,6 +545,68 @@ impl Configuration {
             .to_string()
     }
 
+    /// Pattern for the inline error box; `$MESSAGE$` is replaced with the error message
+    pub fn get_error_template(&self, wiki: &str) -> String {
+        self.error_templates
+            .get(wiki)
+            .or_else(|| self.error_templates.get("default"))
+            .map(|s| s.to_string())
+            .unwrap_or_else(|| {
+                "<div class=\"error\">Listeria could not update this list: $MESSAGE$</div>"
+                    .to_string()
+            })
+    }
+
+    /// Pattern for the inline warnings box; `$MESSAGE$` is replaced with the warnings
+    pub fn get_warning_template(&self, wiki: &str) -> String {
+        self.warning_templates
+            .get(wiki)
+            .or_else(|| self.warning_templates.get("default"))
+            .map(|s| s.to_string())
+            .unwrap_or_else(|| {
+                "<div class=\"warning\">Listeria list definition problems: $MESSAGE$</div>"
+                    .to_string()
+            })
+    }
+
+    /// Call that renders a tabbed data list on the local page; `$DATAPAGE$` is replaced with the data page on Commons
+    pub fn get_tabbed_data_template(&self, wiki: &str) -> String {
+        self.tabbed_data_templates
+            .get(wiki)
+            .or_else(|| self.tabbed_data_templates.get("default"))
+            .map(|s| s.to_string())
+            .unwrap_or_else(|| "{{#invoke:Listeria|tabbed_data|$DATAPAGE$}}".to_string())
+    }
+
+    /// A logged-in API for Commons, where tabbed data pages are written
+    pub async fn get_commons_api(&self) -> Result<Api, String> {
+        let url = match self.get_wbapi("commons") {
+            Some(api) => api.api_url().to_string(),
+            None => "https://commons.wikimedia.org/w/api.php".to_string(),
+        };
+        let mut api = Api::new(&url).await.map_err(|e| e.to_string())?;
+        api.set_oauth2(&self.oauth2_token);
+        Ok(api)
+    }
+
+    /// None means plain links. Without any `citation_templates` configured, `cite web` is used everywhere.
+    pub fn get_citation_template(&self, wiki: &str, language: &str) -> Option<CitationTemplate> {
+        match &self.citation_templates {
+            Some(citation_templates) => citation_templates
+                .get(wiki)
+                .or_else(|| citation_templates.get("default"))
+                .cloned()
+                .flatten(),
+            None => Some(CitationTemplate::new_cite_web(
+                &self.messages.get(language, "cite_web"),
+            )),
+        }
+    }
+
+    pub fn messages(&self) -> &Messages {
+        &self.messages
+    }
+
     pub fn prefer_preferred(&self) -> bool {
         self.prefer_preferred
     }
@@ -228,10 +619,39 @@ impl Configuration {
         self.default_thumbnail_size.unwrap_or(128)
     }
 
+    /// No limit by default
+    pub fn max_rows_per_list(&self) -> Option<usize> {
+        self.max_rows_per_list.map(|i| i as usize)
+    }
+
+    /// Defaults to the MediaWiki default maximum article size (2048 KiB)
+    pub fn max_page_bytes(&self) -> usize {
+        self.max_page_bytes.unwrap_or(2048 * 1024) as usize
+    }
+
+    /// Truncate lists that are too long, instead of failing them
+    pub fn truncate_oversized_lists(&self) -> bool {
+        self.truncate_oversized_lists
+    }
+
+    /// Leave out references that only say a statement was imported from Wikipedia (P143/P4656)
+    pub fn suppress_imported_references(&self) -> bool {
+        self.suppress_imported_references
+    }
+
     pub fn location_regions(&self) -> &Vec<String> {
         &self.location_regions
     }
 
+    /// Returns Some(None) if the entity is known to have no region, None if the entity is not cached
+    pub fn get_cached_region(&self, entity_id: &str) -> Option<Option<String>> {
+        self.region_cache.get(entity_id)
+    }
+
+    pub fn set_cached_region(&self, entity_id: &str, region: Option<String>) {
+        self.region_cache.set(entity_id, region)
+    }
+
     pub async fn wbapi_login(&mut self, key: &str) -> bool {
         let oauth2_token = self.oauth2_token().to_owned();
         match self.wb_apis.get_mut(key) {
@@ -247,6 +667,35 @@ impl Configuration {
         self.wb_apis.get(key)
     }
 
+    pub fn get_sparql_endpoint(&self, key: &str) -> Option<&SparqlEndpoint> {
+        self.sparql_endpoints.get(key)
+    }
+
+    /// Without a configured cookie or token, queries for a configured wikibase use the OAuth2 token its `Api` has,
+    /// like queries through the `Api` did; eg for the Commons query service
+    pub fn with_wbapi_credentials(&self, key: &str, endpoint: SparqlEndpoint) -> SparqlEndpoint {
+        let has_token = !self.oauth2_token.is_empty() && self.wb_apis.contains_key(key);
+        match !endpoint.has_credentials() && has_token {
+            true => endpoint.with_bearer_token(&self.oauth2_token),
+            false => endpoint,
+        }
+    }
+
+    /// SPARQL service at the given URL, with the default (top-level `sparql`) settings
+    pub fn get_default_sparql_endpoint(&self, url: &str) -> Result<SparqlEndpoint, String> {
+        match &self.default_sparql_endpoint {
+            Some(endpoint) => Ok(endpoint.with_url(url)),
+            None => SparqlEndpoint::new_from_json(&json!({ "endpoint": url }), &self.oauth2_token),
+        }
+    }
+
+    pub fn get_wikibase_settings(&self, key: &str) -> WikibaseSettings {
+        self.wikibase_settings
+            .get(key)
+            .cloned()
+            .unwrap_or_default()
+    }
+
     pub fn get_default_wbapi(&self) -> Result<&Arc<Api>, String> {
         self.wb_apis
             .get(&self.default_api)
@@ -0,0 +1,153 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tracing::warn;
+
+/// Prunes a cache directory every `every_writes` writes, in the background.
+/// Walking a large cache tree blocks, so it never runs on the async runtime itself.
+#[derive(Debug)]
+pub struct DiskPruner {
+    path: PathBuf,
+    max_age: Duration,
+    max_size: Option<u64>,
+    every_writes: usize,
+    writes: AtomicUsize,
+    running: Arc<AtomicBool>,
+}
+
+impl DiskPruner {
+    pub fn new(path: PathBuf, max_age: Duration, max_size: Option<u64>, every_writes: usize) -> Self {
+        Self {
+            path,
+            max_age,
+            max_size,
+            every_writes: every_writes.max(1),
+            writes: AtomicUsize::new(0),
+            running: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Counts a write to the cache, and prunes if it is time to
+    pub fn add_write(&self) {
+        if self.writes.fetch_add(1, Ordering::Relaxed) % self.every_writes == self.every_writes - 1 {
+            self.prune();
+        }
+    }
+
+    /// Starts pruning in the background, unless that is already running
+    pub fn prune(&self) {
+        if self.running.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let (path, max_age, max_size) = (self.path.clone(), self.max_age, self.max_size);
+        let running = self.running.clone();
+        let task = move || {
+            prune_directory(&path, max_age, max_size);
+            running.store(false, Ordering::SeqCst);
+        };
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => {
+                handle.spawn_blocking(task);
+            }
+            Err(_) => {
+                std::thread::spawn(task);
+            }
+        }
+    }
+}
+
+/// Removes `.json` files below `path` that were last written more than `max_age` ago.
+/// Then, if `max_size` (in bytes) is set, removes the oldest remaining files until the rest fits.
+pub fn prune_directory(path: &Path, max_age: Duration, max_size: Option<u64>) {
+    let now = SystemTime::now();
+    let mut files: Vec<(SystemTime, u64, PathBuf)> = vec![];
+    for file in json_files(path) {
+        let metadata = match fs::metadata(&file) {
+            Ok(metadata) => metadata,
+            Err(_) => continue, // Removed in the meantime
+        };
+        let modified = metadata.modified().unwrap_or(now);
+        if now.duration_since(modified).unwrap_or_default() > max_age {
+            remove_file(&file);
+        } else {
+            files.push((modified, metadata.len(), file));
+        }
+    }
+    if let Some(max_size) = max_size {
+        let mut total_size: u64 = files.iter().map(|(_, size, _)| size).sum();
+        files.sort(); // Oldest first
+        for (_, size, file) in files {
+            if total_size <= max_size {
+                break;
+            }
+            remove_file(&file);
+            total_size -= size;
+        }
+    }
+}
+
+/// All `.json` files below `path`, in any subdirectory
+fn json_files(path: &Path) -> Vec<PathBuf> {
+    let mut ret = vec![];
+    for entry in fs::read_dir(path).into_iter().flatten().flatten() {
+        let entry_path = entry.path();
+        if entry_path.is_dir() {
+            ret.append(&mut json_files(&entry_path));
+        } else if entry_path.extension().map(|e| e == "json").unwrap_or(false) {
+            ret.push(entry_path);
+        }
+    }
+    ret
+}
+
+fn remove_file(file: &Path) {
+    if let Err(e) = fs::remove_file(file) {
+        warn!("Cannot remove cache file {:?}: {}", file, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::Write;
+
+    /// Writes a file of `bytes` bytes, last modified `age` seconds ago
+    fn write_file(path: &Path, bytes: usize, age: u64) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let mut file = File::create(path).unwrap();
+        file.write_all(&vec![b'x'; bytes]).unwrap();
+        file.set_modified(SystemTime::now() - Duration::from_secs(age))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_prune_directory_by_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let old = dir.path().join("wikidatawiki").join("42").join("Q42.json");
+        write_file(&old, 100, 60);
+        let new = dir.path().join("wikidatawiki").join("43").join("Q43.json");
+        write_file(&new, 100, 0);
+        let other = dir.path().join("README.txt");
+        write_file(&other, 1000, 60);
+
+        prune_directory(dir.path(), Duration::from_secs(3600), Some(150));
+        assert!(!old.exists());
+        assert!(new.exists());
+        assert!(other.exists());
+    }
+
+    #[test]
+    fn test_prune_directory_by_age() {
+        let dir = tempfile::tempdir().unwrap();
+        let old = dir.path().join("ab").join("abcdef.json");
+        write_file(&old, 10, 7200);
+        let new = dir.path().join("cd").join("cdef01.json");
+        write_file(&new, 10, 0);
+        prune_directory(dir.path(), Duration::from_secs(3600), None);
+        assert!(!old.exists());
+        assert!(new.exists());
+    }
+}
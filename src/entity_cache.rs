@@ -0,0 +1,140 @@
+use crate::disk_cache::DiskPruner;
+use chrono::Utc;
+use regex::Regex;
+use serde_json::Value;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::Duration;
+use tempfile::NamedTempFile;
+
+/// The cache directory is pruned after this many writes
+const PRUNE_EVERY_WRITES: usize = 1000;
+
+#[derive(Debug, Clone)]
+pub struct CachedEntity {
+    pub lastrevid: Option<u64>,
+    pub timestamp: i64,
+    pub json: Value,
+}
+
+impl CachedEntity {
+    pub fn age(&self) -> i64 {
+        Utc::now().timestamp() - self.timestamp
+    }
+}
+
+/// On-disk entity cache, shared by all lists and pages, and across bot runs.
+/// Each entity is stored as a JSON file, together with its revision ID and the time it was cached.
+/// Files not written for `max_age` seconds are removed; if `max_size` is set, the oldest files are removed beyond that many bytes.
+#[derive(Debug)]
+pub struct EntityCache {
+    path: PathBuf,
+    ttl: i64,
+    validate_revisions: bool,
+    pruner: DiskPruner,
+}
+
+impl EntityCache {
+    pub fn new(
+        path: PathBuf,
+        ttl: i64,
+        validate_revisions: bool,
+        max_age: i64,
+        max_size: Option<u64>,
+    ) -> Result<Self, String> {
+        fs::create_dir_all(&path)
+            .map_err(|e| format!("Cannot create entity cache directory {:?}: {}", &path, e))?;
+        let max_age = Duration::from_secs(max_age.max(ttl).max(0) as u64);
+        let pruner = DiskPruner::new(path.clone(), max_age, max_size, PRUNE_EVERY_WRITES);
+        pruner.prune(); // Leftovers from earlier runs
+        Ok(Self {
+            path,
+            ttl,
+            validate_revisions,
+            pruner,
+        })
+    }
+
+    /// Reads eg `{"path":"/data/entity_cache","ttl":86400,"validate_revisions":true,"max_age":2592000,"max_size":5000000000}`
+    pub fn new_from_json(j: &Value) -> Result<Option<Self>, String> {
+        let path = match j["path"].as_str() {
+            Some(path) => PathBuf::from(path),
+            None => return Ok(None),
+        };
+        let ttl = j["ttl"].as_i64().unwrap_or(86400);
+        let validate_revisions = j["validate_revisions"].as_bool().unwrap_or(true);
+        let max_age = j["max_age"].as_i64().unwrap_or(30 * 86400);
+        let max_size = j["max_size"].as_u64();
+        Ok(Some(Self::new(
+            path,
+            ttl,
+            validate_revisions,
+            max_age,
+            max_size,
+        )?))
+    }
+
+    /// Seconds during which a cached entity is used without checking its revision
+    pub fn ttl(&self) -> i64 {
+        self.ttl
+    }
+
+    /// If false, cached entities are reloaded after the TTL, instead of comparing revision IDs
+    pub fn validate_revisions(&self) -> bool {
+        self.validate_revisions
+    }
+
+    pub fn is_valid_entity_id(entity_id: &str) -> bool {
+        lazy_static! {
+            static ref RE_ENTITY_ID: Regex =
+                Regex::new(r#"^([PQLM]\d+|L\d+-[FS]\d+)$"#).expect("RE_ENTITY_ID does not parse");
+        }
+        RE_ENTITY_ID.is_match(entity_id)
+    }
+
+    /// Entities are grouped by wikibase (eg `wikidatawiki`), then sharded by the last two characters of their ID
+    fn file_path(&self, wikibase: &str, entity_id: &str) -> Option<PathBuf> {
+        if !Self::is_valid_entity_id(entity_id) || wikibase.contains('/') || wikibase.contains("..") {
+            return None;
+        }
+        let shard = &entity_id[entity_id.len().saturating_sub(2)..];
+        let mut path = self.path.clone();
+        path.push(wikibase);
+        path.push(shard);
+        path.push(format!("{}.json", entity_id));
+        Some(path)
+    }
+
+    pub fn get(&self, wikibase: &str, entity_id: &str) -> Option<CachedEntity> {
+        let path = self.file_path(wikibase, entity_id)?;
+        let text = fs::read_to_string(path).ok()?;
+        let j: Value = serde_json::from_str(&text).ok()?;
+        Some(CachedEntity {
+            lastrevid: j["lastrevid"].as_u64(),
+            timestamp: j["timestamp"].as_i64()?,
+            json: j["entity"].to_owned(),
+        })
+    }
+
+    /// Writes the entity JSON (as returned by wbgetentities) to the cache.
+    /// Writes go to a temporary file first, so concurrent readers never see a partial file.
+    pub fn set(&self, wikibase: &str, entity_id: &str, lastrevid: Option<u64>, json: &Value) -> Result<(), String> {
+        let path = self
+            .file_path(wikibase, entity_id)
+            .ok_or(format!("Invalid entity ID for cache: {}", entity_id))?;
+        let dir = path.parent().ok_or("No parent directory")?;
+        fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+        let j = json!({
+            "lastrevid": lastrevid,
+            "timestamp": Utc::now().timestamp(),
+            "entity": json,
+        });
+        let mut file = NamedTempFile::new_in(dir).map_err(|e| e.to_string())?;
+        file.write_all(j.to_string().as_bytes())
+            .map_err(|e| e.to_string())?;
+        file.persist(&path).map_err(|e| e.to_string())?;
+        self.pruner.add_write();
+        Ok(())
+    }
+}
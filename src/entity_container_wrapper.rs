@@ -1,36 +1,41 @@
+use crate::entity_cache::{CachedEntity, EntityCache};
+use crate::entity_store::EntityStore;
 use crate::listeria_list::ListeriaList;
 use crate::result_cell_part::PartWithReference;
 use crate::result_cell_part::ResultCellPart;
 use crate::result_row::ResultRow;
 use crate::{LinksType, SparqlValue};
-use tempfile::NamedTempFile;
+use futures::stream::{self, Stream, StreamExt};
 use std::collections::HashMap;
 use std::sync::Arc;
-//use std::sync::Mutex;
 use wikibase::entity::*;
 use wikibase::entity_container::EntityContainer;
 use wikibase::mediawiki::api::Api;
-use pickledb::{PickleDb, PickleDbDumpPolicy, SerializationMethod};
 use wikibase::snak::SnakDataType;
-//use std::io::{SeekFrom, Write};
-//use std::io::prelude::*;
+use tracing::warn;
 
-const MAX_LOCAL_CACHED_ENTITIES: usize = usize::MAX; //100;
+/// Above this number of entities, a list keeps its entities in an `EntityStore` on disk
+const MAX_LOCAL_CACHED_ENTITIES: usize = 10000;
+/// Number of entities an `EntityStore` keeps in memory
+const ENTITY_STORE_MEMORY_SIZE: usize = 2000;
+/// Number of wbgetentities requests running at the same time, when loading through the entity cache
+const MAX_CONCURRENT_ENTITY_REQUESTS: usize = 5;
 
 #[derive(Clone)]
 pub struct EntityContainerWrapper {
     entities: EntityContainer,
-    pickledb: Option<Arc<PickleDb>>,
-    pickledb_filename: Option<Arc<NamedTempFile>>,
-    // file_handle: Option<Arc<Mutex<std::fs::File>>>,
-    // entity2pos: HashMap<String,(u64,u64)>,
+    entity_store: Option<Arc<EntityStore>>,
+    number_of_entities_loaded: usize,
+    entity_cache: Option<Arc<EntityCache>>,
+    cached_entities: HashMap<String, Entity>,
 }
 
 impl std::fmt::Debug for EntityContainerWrapper {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("EntityContainerWrapper")
          .field("entities", &self.entities)
-         .field("pickledb_filename", &self.pickledb_filename)
+         .field("entity_store", &self.entity_store)
+         .field("entity_cache", &self.entity_cache)
          .finish()
     }
 }
@@ -39,102 +44,233 @@ impl EntityContainerWrapper {
     pub fn new() -> Self {
         Self {
             entities: EntityContainer::new(),
-            pickledb: None,
-            pickledb_filename: None,
-            // file_handle: None,
-            // entity2pos: HashMap::new(),
-        }
-    }
-/*
-    fn hashfile_add_entity(&mut self, entity_id: &str, json: serde_json::Value) {
-        match &self.file_handle {
-            Some(fh) => {
-                let mut fh = fh.lock().unwrap();
-                let start = fh.metadata().unwrap().len();
-                let json_string = json.to_string();
-                println!("Writing data for {entity_id}");
-                write!(fh, "{json_string}").unwrap();
-                fh.sync_all().unwrap();
-                let end = fh.metadata().unwrap().len();
-                let len = end-start;
-                self.entity2pos.insert(entity_id.to_string(), (start,len));        
-            },
-            None => panic!("add_entity: No file handle"),
+            entity_store: None,
+            number_of_entities_loaded: 0,
+            entity_cache: None,
+            cached_entities: HashMap::new(),
         }
     }
 
-    fn hashfile_create(&mut self) {
-        self.file_handle = Some(Arc::new(Mutex::new(tempfile().unwrap())));
+    pub async fn load_entities(&mut self, api: &Api, ids: &Vec<String>) -> Result<(), String> {
+        self.load_entities_max_size(api, ids, MAX_LOCAL_CACHED_ENTITIES).await
     }
 
-    fn hashfile_get_entity(&self, entity_id: &str) -> Option<Entity> {
-        let (start,len) = self.entity2pos.get(entity_id)?;
-        match &self.file_handle {
-            Some(fh) => {
-                let mut fh = fh.lock().ok()?;
-                fh.seek(SeekFrom::Start(*start)).ok()?;
-                let mut buffer = Vec::with_capacity(*len as usize);
-                fh.read_exact(&mut buffer).ok()?;
-                let json_string = String::from_utf8(buffer).ok()?;
-                let json = serde_json::from_str(&json_string).ok()?;
-                Entity::new_from_json(&json).ok()
-        
-            },
-            None => panic!("add_entity: No file handle"),
+    pub async fn load_entities_max_size(&mut self, api: &Api, ids: &Vec<String>, max_entities: usize) -> Result<(), String> {
+        let mut ids = self.entities.unique_shuffle_entity_ids(ids).unwrap();
+        if let Some(store) = &self.entity_store {
+            ids.retain(|id| !store.contains(id));
+        }
+        let store_memory_size = ENTITY_STORE_MEMORY_SIZE.min(max_entities).max(1);
+        let entities_in_memory = self.entities.len() + self.cached_entities.len();
+        if entities_in_memory + ids.len() > max_entities && self.entity_store.is_none() {
+            // From now on, new entities go to the bounded store.
+            // Entities loaded before remain in `self.entities`; cached ones move to the store.
+            let store = EntityStore::new(store_memory_size)
+                .map_err(|e| format!("Error loading entities: {}", &e))?;
+            for (entity_id, entity) in self.cached_entities.drain() {
+                store.set(&entity_id, entity)?;
+            }
+            self.entity_store = Some(Arc::new(store));
+        }
+        if let Some(entity_cache) = self.entity_cache.clone() {
+            return self.load_entities_with_cache(api, &ids, &entity_cache).await;
+        }
+        self.number_of_entities_loaded += ids.len();
+        match &self.entity_store {
+            Some(store) => {
+                // Load into a separate container, so entities already in `self.entities` are kept.
+                // Chunks are no larger than the store keeps in memory anyway.
+                let chunk_entities = EntityContainer::new();
+                for chunk in ids.chunks(store_memory_size) {
+                    if let Err(e) = chunk_entities.load_entities(api, &chunk.into()).await {
+                        return Err(format!("Error loading entities: {:?}", &e))
+                    }
+                    for entity_id in chunk {
+                        if let Some(entity) = chunk_entities.get_entity(entity_id) {
+                            store.set(entity_id, entity)?;
+                        }
+                    }
+                    chunk_entities.clear();
+                }
+                Ok(())
+            }
+            None => match self.entities.load_entities(api, &ids).await {
+                Ok(_) => Ok(()),
+                Err(e) => Err(format!("Error loading entities: {:?}", &e)),
+            }
         }
     }
-*/
-    pub async fn load_entities(&mut self, api: &Api, ids: &Vec<String>) -> Result<(), String> {
-        self.load_entities_max_size(api, ids, MAX_LOCAL_CACHED_ENTITIES).await
+
+    pub fn set_entity_cache(&mut self, entity_cache: Option<Arc<EntityCache>>) {
+        self.entity_cache = entity_cache;
     }
 
-    pub async fn load_entities_max_size(&mut self, api: &Api, ids: &Vec<String>, max_entities: usize) -> Result<(), String> {
-        let ids = self.entities.unique_shuffle_entity_ids(ids).unwrap();
-        if ids.len()>max_entities { // Use pickledb disk cache
-            // self.hashfile_create();
-            self.pickledb_filename = Some(Arc::new(            
-                match  NamedTempFile::new() {
-                    Ok(filename) => filename,
-                    Err(e) => return Err(format!("Error loading entities: {}", &e.to_string()))
+    /// Uses cached entities where they are recent enough, or their revision has not changed.
+    /// Loads all other entities from the API, and writes them to the cache.
+    async fn load_entities_with_cache(
+        &mut self,
+        api: &Api,
+        ids: &[String],
+        entity_cache: &EntityCache,
+    ) -> Result<(), String> {
+        let wikibase = match api.get_site_info_string("general", "wikiid") {
+            Ok(wikiid) => wikiid.to_string(),
+            Err(_) => "wikibase".to_string(),
+        };
+        let mut to_validate: Vec<(String, CachedEntity)> = vec![];
+        let mut to_load: Vec<String> = vec![];
+        for id in ids {
+            if self.has_loaded_entity(id) || !EntityCache::is_valid_entity_id(id) {
+                continue;
+            }
+            match entity_cache.get(&wikibase, id) {
+                Some(cached) if cached.age() < entity_cache.ttl() => {
+                    self.add_cached_entity(id, &cached.json)
                 }
-            ));
-            let temp_filename = self.pickledb_filename.as_ref().unwrap().path().to_str().unwrap();
-            let mut db = PickleDb::new(
-                temp_filename,
-                PickleDbDumpPolicy::AutoDump,
-                SerializationMethod::Json,
-            );
-            let chunks = ids.chunks(max_entities) ;
-            for chunk in chunks {
-                if let Err(e) = self.entities.load_entities(api, &chunk.into()).await {
-                    return Err(format!("Error loading entities: {:?}", &e))
+                Some(cached) if entity_cache.validate_revisions() && cached.lastrevid.is_some() => {
+                    to_validate.push((id.to_owned(), cached))
                 }
-                for entity_id in chunk {
-                    if let Some(entity) = self.entities.get_entity(entity_id) {
-                        let json = entity.to_json();
-                        //let _ = self.hashfile_add_entity(&entity.id(), json);
-                        db.set(&entity.id(), &json).unwrap();
+                _ => to_load.push(id.to_owned()),
+            }
+        }
+
+        if !to_validate.is_empty() {
+            let ids_to_validate: Vec<String> =
+                to_validate.iter().map(|(id, _)| id.to_owned()).collect();
+            let revisions = self.get_revision_ids(api, &ids_to_validate).await?;
+            for (id, cached) in to_validate {
+                if revisions.get(&id) == cached.lastrevid.as_ref() {
+                    // Unchanged, renew TTL
+                    if let Err(e) = entity_cache.set(&wikibase, &id, cached.lastrevid, &cached.json) {
+                        warn!("Cannot write {} to entity cache: {}", &id, e);
                     }
+                    self.add_cached_entity(&id, &cached.json);
+                } else {
+                    to_load.push(id);
                 }
-                self.entities.clear();
             }
-            self.pickledb = Some(Arc::new(db));
-            Ok(())
-        } else {
-            match self.entities.load_entities(api, &ids).await {
-                Ok(_) => Ok(()),
-                Err(e) => Err(format!("Error loading entities: {:?}", &e)),
+        }
+
+        self.number_of_entities_loaded += to_load.len();
+        // Store each chunk as it arrives, so the entity store can keep memory bounded
+        let props = "info|aliases|labels|descriptions|claims|datatype|sitelinks";
+        let mut chunks = Self::get_entities_json_chunks(api, &to_load, props);
+        while let Some(result) = chunks.next().await {
+            for (id, json) in result? {
+                if let Err(e) = entity_cache.set(&wikibase, &id, json["lastrevid"].as_u64(), &json) {
+                    warn!("Cannot write {} to entity cache: {}", &id, e);
+                }
+                self.add_cached_entity(&id, &json);
+            }
+        }
+        Ok(())
+    }
+
+    async fn get_revision_ids(&self, api: &Api, ids: &[String]) -> Result<HashMap<String, u64>, String> {
+        Ok(self
+            .get_entities_json(api, ids, "info")
+            .await?
+            .iter()
+            .filter_map(|(id, json)| Some((id.to_owned(), json["lastrevid"].as_u64()?)))
+            .collect())
+    }
+
+    /// Runs wbgetentities in chunks of 50 IDs, returns the JSON of all existing entities
+    async fn get_entities_json(
+        &self,
+        api: &Api,
+        ids: &[String],
+        props: &str,
+    ) -> Result<Vec<(String, serde_json::Value)>, String> {
+        let mut ret = vec![];
+        let mut chunks = Self::get_entities_json_chunks(api, ids, props);
+        while let Some(result) = chunks.next().await {
+            ret.append(&mut result?);
+        }
+        Ok(ret)
+    }
+
+    /// Runs wbgetentities in chunks of 50 IDs, with a limited number of requests at a time.
+    /// Yields the JSON of the existing entities of each chunk, as soon as it arrives.
+    fn get_entities_json_chunks<'a>(
+        api: &'a Api,
+        ids: &'a [String],
+        props: &'a str,
+    ) -> impl Stream<Item = Result<Vec<(String, serde_json::Value)>, String>> + Unpin + 'a {
+        stream::iter(ids.chunks(50))
+            .map(move |chunk| Self::get_entities_json_chunk(api, chunk, props))
+            .buffer_unordered(MAX_CONCURRENT_ENTITY_REQUESTS)
+    }
+
+    async fn get_entities_json_chunk(
+        api: &Api,
+        ids: &[String],
+        props: &str,
+    ) -> Result<Vec<(String, serde_json::Value)>, String> {
+        let params: HashMap<String, String> = vec![
+            ("action", "wbgetentities"),
+            ("ids", ids.join("|").as_str()),
+            ("props", props),
+        ]
+        .iter()
+        .map(|x| (x.0.to_string(), x.1.to_string()))
+        .collect();
+        let j = api
+            .get_query_api_json(&params)
+            .await
+            .map_err(|e| format!("Error loading entities: {}", e))?;
+        if let Some(error) = j["error"]["info"].as_str() {
+            return Err(format!("Error loading entities: {}", error));
+        }
+        let mut ret = vec![];
+        if let Some(entities) = j["entities"].as_object() {
+            for (id, json) in entities.iter() {
+                if json.get("missing").is_none() {
+                    ret.push((id.to_owned(), json.to_owned()));
+                }
+            }
+        }
+        Ok(ret)
+    }
+
+    fn add_cached_entity(&mut self, entity_id: &str, json: &serde_json::Value) {
+        if let Ok(entity) = Entity::new_from_json(json) {
+            match &self.entity_store {
+                Some(store) => {
+                    if let Err(e) = store.set(entity_id, entity) {
+                        warn!("Cannot store {}: {}", entity_id, e);
+                    }
+                }
+                None => {
+                    self.cached_entities.insert(entity_id.to_string(), entity);
+                }
             }
         }
     }
 
+    /// Entity was loaded before, either from the API or from the entity cache
+    fn has_loaded_entity(&self, entity_id: &str) -> bool {
+        self.cached_entities.contains_key(entity_id)
+            || self
+                .entity_store
+                .as_ref()
+                .map(|store| store.contains(entity_id))
+                .unwrap_or(false)
+    }
+
+    /// Number of entities requested from the Wikibase API; cache hits are not counted
+    pub fn number_of_entities_loaded(&self) -> usize {
+        self.number_of_entities_loaded
+    }
+
     pub fn get_entity(&self, entity_id: &str) -> Option<Entity> {
         if let Some(entity) = self.entities.get_entity(entity_id) {
             return Some(entity)
         }
-        // self.hashfile_get_entity(entity_id)
-        let json = self.pickledb.as_ref()?.get::<serde_json::Value>(entity_id)?;
-        Entity::new_from_json(&json).ok()
+        if let Some(entity) = self.cached_entities.get(entity_id) {
+            return Some(entity.to_owned())
+        }
+        self.entity_store.as_ref()?.get(entity_id)
     }
 
     pub fn get_local_entity_label(&self, entity_id: &str, language: &str) -> Option<String> {
@@ -257,18 +393,30 @@ mod tests {
     use super::*;
 
     #[tokio::test]
-    async fn test_pickledb() {
+    async fn test_entity_store() {
         let mut ecw = EntityContainerWrapper::new();
         let api = wikibase::mediawiki::api::Api::new("https://www.wikidata.org/w/api.php").await.unwrap();
         let ids = ["Q1","Q2","Q3","Q4","Q5"].iter().map(|s|s.to_string()).collect();
         ecw.load_entities_max_size(&api, &ids, 2).await.unwrap();
         assert_eq!(ecw.entities.len(),0);
-
-        let path = ecw.pickledb_filename.as_ref().unwrap().path();
-        let len = std::fs::metadata(path).unwrap().len();
-        assert!(len>0);
+        assert_eq!(ecw.entity_store.as_ref().unwrap().len(),5);
 
         let e2 = ecw.get_entity("Q2").unwrap();
         assert_eq!(e2.id(),"Q2");
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_entity_store_keeps_earlier_entities() {
+        let mut ecw = EntityContainerWrapper::new();
+        let api = wikibase::mediawiki::api::Api::new("https://www.wikidata.org/w/api.php").await.unwrap();
+        let ids = ["Q1","Q2"].iter().map(|s|s.to_string()).collect();
+        ecw.load_entities_max_size(&api, &ids, 2).await.unwrap();
+        assert!(ecw.entity_store.is_none());
+        let ids = ["Q2","Q3","Q4","Q5"].iter().map(|s|s.to_string()).collect();
+        ecw.load_entities_max_size(&api, &ids, 2).await.unwrap();
+        assert_eq!(ecw.entity_store.as_ref().unwrap().len(),3);
+        assert_eq!(ecw.get_entity("Q1").unwrap().id(),"Q1");
+        assert_eq!(ecw.get_entity("Q5").unwrap().id(),"Q5");
+        assert_eq!(ecw.number_of_entities_loaded(),5);
+    }
+}
@@ -0,0 +1,91 @@
+use lru::LruCache;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::num::NonZeroUsize;
+use std::sync::{Mutex, RwLock};
+use wikibase::entity::*;
+
+/// Bounded entity storage for very large lists.
+/// All entities are written as compact JSON to an anonymous temporary file (removed automatically);
+/// only the most recently used ones are kept in memory.
+pub struct EntityStore {
+    file: Mutex<File>,
+    positions: RwLock<HashMap<String, (u64, usize)>>, // Entity ID => (offset,length) in file
+    memory: Mutex<LruCache<String, Entity>>,
+}
+
+impl std::fmt::Debug for EntityStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EntityStore")
+            .field("entities", &self.len())
+            .finish()
+    }
+}
+
+impl EntityStore {
+    pub fn new(memory_size: usize) -> Result<Self, String> {
+        let memory_size = NonZeroUsize::new(memory_size).ok_or("EntityStore memory size must not be 0")?;
+        Ok(Self {
+            file: Mutex::new(tempfile::tempfile().map_err(|e| e.to_string())?),
+            positions: RwLock::new(HashMap::new()),
+            memory: Mutex::new(LruCache::new(memory_size)),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.positions.read().map(|p| p.len()).unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains(&self, entity_id: &str) -> bool {
+        self.positions
+            .read()
+            .map(|p| p.contains_key(entity_id))
+            .unwrap_or(false)
+    }
+
+    pub fn set(&self, entity_id: &str, entity: Entity) -> Result<(), String> {
+        let json_string = entity.to_json().to_string();
+        let position = {
+            let mut file = self.file.lock().map_err(|e| e.to_string())?;
+            let start = file.seek(SeekFrom::End(0)).map_err(|e| e.to_string())?;
+            file.write_all(json_string.as_bytes())
+                .map_err(|e| e.to_string())?;
+            (start, json_string.len())
+        };
+        self.positions
+            .write()
+            .map_err(|e| e.to_string())?
+            .insert(entity_id.to_string(), position);
+        self.memory
+            .lock()
+            .map_err(|e| e.to_string())?
+            .put(entity_id.to_string(), entity);
+        Ok(())
+    }
+
+    pub fn get(&self, entity_id: &str) -> Option<Entity> {
+        if let Some(entity) = self.memory.lock().ok()?.get(&entity_id.to_string()) {
+            return Some(entity.to_owned());
+        }
+        let (start, len) = *self.positions.read().ok()?.get(entity_id)?;
+        let buffer = {
+            let mut file = self.file.lock().ok()?;
+            file.seek(SeekFrom::Start(start)).ok()?;
+            let mut buffer = vec![0; len];
+            file.read_exact(&mut buffer).ok()?;
+            buffer
+        };
+        let json: serde_json::Value = serde_json::from_slice(&buffer).ok()?;
+        let entity = Entity::new_from_json(&json).ok()?;
+        self.memory
+            .lock()
+            .ok()?
+            .put(entity_id.to_string(), entity.to_owned());
+        Some(entity)
+    }
+}
@@ -5,22 +5,41 @@ extern crate serde_json;
 
 pub mod column;
 pub mod configuration;
+pub mod disk_cache;
+pub mod entity_cache;
 pub mod entity_container_wrapper;
+pub mod entity_store;
+pub mod list_snapshot;
 pub mod listeria_list;
 pub mod listeria_page;
 pub mod listeria_bot;
+pub mod logging;
+pub mod messages;
+pub mod metrics;
 pub mod reference;
 pub mod render_tabbed_data;
 pub mod render_wikitext;
 pub mod result_cell;
 pub mod result_cell_part;
 pub mod result_row;
+pub mod sparql;
+pub mod sparql_cache;
+pub mod table_diff;
+pub mod template_tokenizer;
+pub mod validation;
 
 use crate::column::*;
 use crate::configuration::Configuration;
 use crate::listeria_list::ListeriaList;
+use crate::list_snapshot::ListSnapshot;
 use crate::listeria_page::ListeriaPage;
+use crate::metrics::Metrics;
+use crate::reference::Reference;
+use crate::render_tabbed_data::RendererTabbedData;
 use crate::render_wikitext::RendererWikitext;
+use crate::table_diff::{is_semantically_equal, TableDiff};
+use crate::template_tokenizer::{find_template_end, split_template_params};
+use crate::validation::ValidationWarning;
 use regex::Regex;
 use regex::RegexBuilder;
 use serde_json::Value;
@@ -45,6 +64,7 @@ pub struct PageParams {
     simulated_autodesc: Option<Vec<String>>,
     config: Arc<Configuration>,
     local_file_namespace_prefix: String,
+    metrics: Option<Arc<Metrics>>,
 }
 
 impl PageParams {
@@ -69,6 +89,7 @@ impl PageParams {
                 .get_local_namespace_name(6)
                 .unwrap_or("File")
                 .to_string(),
+            metrics: None,
         };
         Ok(ret)
     }
@@ -76,6 +97,10 @@ impl PageParams {
     pub fn local_file_namespace_prefix(&self) -> &String {
         &self.local_file_namespace_prefix
     }
+
+    pub fn metrics(&self) -> &Option<Arc<Metrics>> {
+        &self.metrics
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -98,10 +123,16 @@ pub enum SparqlValue {
     Time(String),
     Location(LatLon),
     Literal(String),
+    Boolean(bool),
 }
 
 impl SparqlValue {
     pub fn new_from_json(j: &Value) -> Option<Self> {
+        Self::new_from_json_with_concept_uri(j, None)
+    }
+
+    /// If `concept_uri` is given, only URIs with that prefix are treated as entities
+    pub fn new_from_json_with_concept_uri(j: &Value, concept_uri: Option<&str>) -> Option<Self> {
         lazy_static! {
             static ref RE_ENTITY: Regex =
                 Regex::new(r#"^https{0,1}://[^/]+/entity/([A-Z]\d+)$"#).expect("RE_ENTITY does not parse");
@@ -116,8 +147,12 @@ impl SparqlValue {
             Some(v) => v,
             None => return None,
         };
+        let entity_caps = match concept_uri {
+            Some(concept_uri) if !value.starts_with(concept_uri) => None,
+            _ => RE_ENTITY.captures(&value),
+        };
         match j["type"].as_str() {
-            Some("uri") => match RE_ENTITY.captures(&value) {
+            Some("uri") => match entity_caps {
                 Some(caps) => match caps.get(1) {
                     Some(caps1) => Some(SparqlValue::Entity(caps1.as_str().to_string())),
                     None => None,
@@ -157,6 +192,9 @@ impl SparqlValue {
                     };
                     Some(SparqlValue::Time(time))
                 }
+                Some("http://www.w3.org/2001/XMLSchema#boolean") => {
+                    Some(SparqlValue::Boolean(value == "true" || value == "1"))
+                }
                 _ => Some(SparqlValue::Literal(value.to_string())),
             },
             Some("bnode") => match j["value"].as_str() {
@@ -175,48 +213,8 @@ pub struct Template {
 }
 
 impl Template {
-    pub fn new_from_params(title: String, text: String) -> Result<Self,String> {
-        let mut curly_braces = 0;
-        let mut parts: Vec<String> = vec![];
-        let mut part: Vec<char> = vec![];
-        let mut quoted = false ;
-        let mut quote_char : char = ' ' ;
-        text.chars().for_each(|c| match c {
-            '\''|'"' => {
-                if quoted {
-                    if quote_char == c {
-                        quoted = false ;
-                    }
-                } else {
-                    quoted = true ;
-                    quote_char = c ;
-                }
-                part.push(c);
-            }
-            '{' => {
-                curly_braces += 1;
-                part.push(c);
-            }
-            '}' => {
-                curly_braces -= 1;
-                part.push(c);
-            }
-            '|' => {
-                if curly_braces == 0 && !quoted {
-                    parts.push(part.iter().collect());
-                    part.clear();
-                } else {
-                    part.push(c);
-                }
-            }
-            _ => {
-                part.push(c);
-            }
-        });
-        parts.push(part.into_iter().collect());
-        if quoted==true {
-            return Err ( format!("Unclosed quote: {}",quote_char) ) ;
-        }
+    pub fn new_from_params(title: String, text: String) -> Result<Self, String> {
+        let parts = split_template_params(&text)?;
 
         let params: HashMap<String, String> = parts
             .iter()
@@ -323,19 +321,74 @@ impl SortOrder {
 pub enum ReferencesParameter {
     None,
     All,
+    First,                // Only the first reference of each statement
+    NoWikipedia,          // Without references that were only imported from Wikipedia
+    WithUrl,              // Only references with a (reference or archive) URL
+    Columns(Vec<String>), // All references, but only for these properties, eg `references=P569,P570`
 }
 
 impl ReferencesParameter {
     pub fn new(os: Option<&String>) -> Self {
-        match os {
-            Some(s) => {
-                if s.to_uppercase().trim() == "ALL" {
-                    Self::All
+        lazy_static! {
+            static ref RE_PROPERTY: Regex = Regex::new(r#"^P\d+$"#).expect("RE_PROPERTY does not parse");
+        }
+        let s = match os {
+            Some(s) => s.trim().to_uppercase(),
+            None => return Self::None,
+        };
+        match s.as_str() {
+            "ALL" => Self::All,
+            "FIRST" => Self::First,
+            "NOWIKIPEDIA" | "NO_WIKIPEDIA" => Self::NoWikipedia,
+            "WITHURL" | "WITH_URL" => Self::WithUrl,
+            _ => {
+                let properties: Vec<String> = s.split(',').map(|p| p.trim().to_string()).collect();
+                if properties.iter().all(|p| RE_PROPERTY.is_match(p)) {
+                    Self::Columns(properties)
                 } else {
                     Self::None
                 }
             }
-            None => Self::None,
+        }
+    }
+
+    /// The references of a statement to show in this mode; all of them for `Columns`,
+    /// which is about which statements get references at all
+    pub fn filter(&self, references: Vec<Reference>, suppress_imported: bool) -> Vec<Reference> {
+        let mut ret = vec![];
+        for reference in references {
+            if suppress_imported && reference.is_imported() {
+                continue;
+            }
+            match self {
+                Self::None => break,
+                Self::NoWikipedia if reference.is_imported() => continue,
+                Self::WithUrl if !reference.has_url() => continue,
+                _ => {}
+            }
+            ret.push(reference);
+            if *self == Self::First {
+                break;
+            }
+        }
+        ret
+    }
+}
+
+/// What to do with the page when a list fails
+#[derive(Debug, Clone, PartialEq)]
+pub enum OnErrorMode {
+    Fail,    // Leave the page alone; status FAIL only
+    Keep,    // Show an error box above the last good list
+    Replace, // Replace the list with an error box
+}
+
+impl OnErrorMode {
+    pub fn new(os: Option<&String>) -> Self {
+        match os.map(|s| s.trim().to_uppercase()) {
+            Some(s) if s == "KEEP" => Self::Keep,
+            Some(s) if s == "REPLACE" => Self::Replace,
+            _ => Self::Fail,
         }
     }
 }
@@ -347,6 +400,8 @@ pub struct TemplateParams {
     section: SectionType,
     min_section: u64,
     row_template: Option<String>,
+    row_template_raw: bool,
+    row_template_vars: Vec<String>,
     header_template: Option<String>,
     autodesc: Option<String>,
     summary: Option<String>,
@@ -356,6 +411,9 @@ pub struct TemplateParams {
     one_row_per_item: bool,
     sort_order: SortOrder,
     wikibase: String,
+    cache: Option<i64>,
+    paginate: Option<usize>,
+    tabbed_data: bool,
 }
 
 impl Default for TemplateParams {
@@ -372,6 +430,8 @@ impl TemplateParams {
             section: SectionType::None,
             min_section: 2,
             row_template: None,
+            row_template_raw: false,
+            row_template_vars: vec![],
             header_template: None,
             autodesc: None,
             summary: None,
@@ -381,6 +441,9 @@ impl TemplateParams {
             one_row_per_item: false,
             sort_order: SortOrder::Ascending,
             wikibase: String::new(),
+            cache: None,
+            paginate: None,
+            tabbed_data: false,
         }
     }
 
@@ -398,6 +461,21 @@ impl TemplateParams {
                 .params
                 .get("row_template")
                 .map(|s| s.trim().to_string()),
+            row_template_raw: template
+                .params
+                .get("row_template_raw")
+                .map(|s| s.trim().to_uppercase())
+                == Some("YES".to_string()),
+            row_template_vars: template
+                .params
+                .get("row_template_vars")
+                .map(|s| {
+                    s.split(',')
+                        .map(|v| v.trim().trim_start_matches('?').to_string())
+                        .filter(|v| !v.is_empty())
+                        .collect()
+                })
+                .unwrap_or_default(),
             header_template: template
                 .params
                 .get("header_template")
@@ -434,6 +512,26 @@ impl TemplateParams {
                 .get("wikibase")
                 .map(|s| s.trim().to_uppercase())
                 .unwrap_or_else(|| "wikidata".to_string()), // TODO config
+            cache: Self::parse_cache(template.params.get("cache")),
+            paginate: template
+                .params
+                .get("paginate")
+                .and_then(|s| s.trim().parse::<usize>().ok())
+                .filter(|i| *i > 0),
+            tabbed_data: template
+                .params
+                .get("tabbed_data")
+                .map(|s| !matches!(s.trim().to_uppercase().as_str(), "" | "0" | "NO" | "FALSE"))
+                .unwrap_or(false),
+        }
+    }
+
+    /// `cache=no` bypasses the SPARQL result cache; `cache=SECONDS` limits the age of a cached result
+    fn parse_cache(s: Option<&String>) -> Option<i64> {
+        let s = s?.trim().to_lowercase();
+        match s.as_str() {
+            "no" | "false" => Some(0),
+            _ => s.parse::<i64>().ok().map(|i| i.max(0)),
         }
     }
 }
@@ -489,10 +587,15 @@ pub struct PageElement {
     after: String,
     list: ListeriaList,
     is_just_text: bool,
+    list_number: usize, // 1-based, among the lists on the page
+    max_rows_per_page: Option<usize>, // Lower than `paginate`, if pages would be too large
+    on_error: OnErrorMode,
+    error: Option<String>,
 }
 
 impl PageElement {
-    pub fn new_from_text(text: &str, page: &ListeriaPage) -> Option<Self> {
+    /// Regular expressions matching the start and end templates on the page's wiki
+    pub fn template_patterns(page: &ListeriaPage) -> Option<(Regex, Regex)> {
         let start_template = page
             .config()
             .get_local_template_title_start(&page.wiki())
@@ -501,6 +604,11 @@ impl PageElement {
             .config()
             .get_local_template_title_end(&page.wiki())
             .ok()?;
+        Self::template_patterns_for(&start_template, &end_template)
+    }
+
+    /// Regular expressions matching the given start and end templates, as well as the English ones
+    pub fn template_patterns_for(start_template: &str, end_template: &str) -> Option<(Regex, Regex)> {
         let pattern_string_start = r#"\{\{(Wikidata[ _]list[^\|]*|"#.to_string()
             + &start_template.replace(" ", "[ _]")
             //+ r#")\s*\|"#; // New version
@@ -521,6 +629,11 @@ impl PageElement {
             .case_insensitive(true)
             .build()
             .ok()?;
+        Some((seperator_start, seperator_end))
+    }
+
+    pub fn new_from_text(text: &str, page: &ListeriaPage) -> Option<Self> {
+        let (seperator_start, seperator_end) = Self::template_patterns(page)?;
 
         let match_start = match seperator_start.find(&text) {
             Some(m) => m,
@@ -559,6 +672,7 @@ impl PageElement {
             )
             .ok()?,
         ).ok()?;
+        let on_error = OnErrorMode::new(template.params.get("on_error"));
 
         Some(Self {
             before: String::from_utf8(text.as_bytes()[0..match_start.start()].to_vec()).ok()?,
@@ -573,9 +687,18 @@ impl PageElement {
                 String::from_utf8(text.as_bytes()[match_end.start()..match_end.end()].to_vec())
                     .ok()?
             },
-            after: String::from_utf8(text.as_bytes()[match_end.end()..].to_vec()).ok()?,
+            // Without an end template, `match_end` is the start template match, so its end would cut into the template parameters
+            after: if single_template {
+                String::from_utf8(text.as_bytes()[template_start_end_bytes..].to_vec()).ok()?
+            } else {
+                String::from_utf8(text.as_bytes()[match_end.end()..].to_vec()).ok()?
+            },
             list: ListeriaList::new(template, page.page_params()),
             is_just_text: false,
+            list_number: 1,
+            max_rows_per_page: None,
+            on_error,
+            error: None,
         })
     }
 
@@ -592,6 +715,10 @@ impl PageElement {
             after: String::new(),
             list: ListeriaList::new(template, page.page_params()),
             is_just_text: true,
+            list_number: 0,
+            max_rows_per_page: None,
+            on_error: OnErrorMode::Fail,
+            error: None,
         }
     }
 
@@ -601,56 +728,287 @@ impl PageElement {
         ret
     }
 
+    pub fn set_list_number(&mut self, list_number: usize) {
+        self.list_number = list_number;
+    }
+
+    /// With `paginate=N`, only the first N rows, and an index of the subpages
     pub fn new_inside(&self) -> Result<String, String> {
-        match self.is_just_text {
-            true => Ok(String::new()),
-            false => {
+        if self.is_just_text {
+            return Ok(String::new());
+        }
+        if self.is_tabbed_data() {
+            let data_page = RendererTabbedData::new()
+                .tabbed_data_page_name(&self.list, self.list_number)
+                .ok_or("Data page name too long")?;
+            return Ok(self
+                .list
+                .get_tabbed_data_template()
+                .replace("$DATAPAGE$", &data_page));
+        }
+        match self.rows_per_page() {
+            Some(rows_per_page) => {
+                let mut renderer = RendererWikitext::new_for_rows(0..rows_per_page);
+                Ok(renderer.render(&self.list)? + "\n" + &self.pagination_links(1))
+            }
+            None => {
                 let mut renderer = RendererWikitext::new();
                 renderer.render(&self.list)
             }
         }
     }
 
+    /// Only set if the list is paginated, and has more than one page
+    fn rows_per_page(&self) -> Option<usize> {
+        if self.is_tabbed_data() {
+            return None;
+        }
+        let rows_per_page = self.list.template_params().paginate?;
+        let rows_per_page = match self.max_rows_per_page {
+            Some(max_rows_per_page) => rows_per_page.min(max_rows_per_page),
+            None => rows_per_page,
+        };
+        match self.list.results().len() > rows_per_page {
+            true => Some(rows_per_page),
+            false => None,
+        }
+    }
+
+    pub fn number_of_pages(&self) -> usize {
+        match self.rows_per_page() {
+            Some(rows_per_page) => (self.list.results().len() + rows_per_page - 1) / rows_per_page,
+            None => 1,
+        }
+    }
+
+    /// Page 1 is the page with the list template; others are subpages, eg `Page/2`, or `Page/3/2` for the third list on the page
+    pub fn subpage_title(&self, page_number: usize) -> String {
+        let title = self.list.page_title();
+        match (page_number, self.list_number) {
+            (1, _) => title.to_owned(),
+            (_, 1) => format!("{}/{}", title, page_number),
+            (_, list_number) => format!("{}/{}/{}", title, list_number, page_number),
+        }
+    }
+
+    fn pagination_links(&self, current_page: usize) -> String {
+        let links: Vec<String> = (1..=self.number_of_pages())
+            .map(|page_number| match page_number == current_page {
+                true => format!("'''{}'''", page_number),
+                false => format!("[[{}|{}]]", self.subpage_title(page_number), page_number),
+            })
+            .collect();
+        format!("<div class=\"listeria-pagination\">{}</div>", links.join(" · "))
+    }
+
+    /// Titles and wikitext of the subpages of a paginated list, without the first page
+    pub fn subpages(&self) -> Result<Vec<(String, String)>, String> {
+        if self.is_just_text || self.error.is_some() {
+            return Ok(vec![]);
+        }
+        let rows_per_page = match self.rows_per_page() {
+            Some(rows_per_page) => rows_per_page,
+            None => return Ok(vec![]),
+        };
+        (2..=self.number_of_pages())
+            .map(|page_number| {
+                let start = (page_number - 1) * rows_per_page;
+                let mut renderer = RendererWikitext::new_for_rows(start..start + rows_per_page);
+                let links = self.pagination_links(page_number);
+                let wikitext = links.to_owned() + "\n" + &renderer.render(&self.list)? + "\n" + &links;
+                Ok((self.subpage_title(page_number), wikitext))
+            })
+            .collect()
+    }
+
     pub fn as_wikitext(&self) -> Result<String, String> {
+        if self.is_just_text {
+            return Ok(self.before.clone());
+        }
+        let inside = match &self.error {
+            Some(error) => {
+                let error_box = self.error_box(error);
+                match self.on_error {
+                    OnErrorMode::Keep => {
+                        error_box + "\n" + Self::remove_error_box(&self._inside).trim()
+                    }
+                    _ => error_box,
+                }
+            }
+            None => self.warnings_box() + &self.new_inside()?,
+        };
+        Ok(self.before.clone()
+            + &self.template_start
+            + "\n"
+            + &inside
+            + "\n"
+            + &self.template_end_as_written()
+            + &self.after)
+    }
+
+    /// Without an end template, the list would be added again on every run
+    fn template_end_as_written(&self) -> String {
+        match self.template_end.is_empty() {
+            true => self.list.get_end_template(),
+            false => self.template_end.to_owned(),
+        }
+    }
+
+    /// The text this element was created from
+    pub fn original_wikitext(&self) -> String {
         match self.is_just_text {
-            true => Ok(self.before.clone()),
-            false => Ok(self.before.clone()
-                + &self.template_start
-                + "\n"
-                + &self.new_inside()?
-                + "\n"
-                + &self.template_end
-                + &self.after),
+            true => self.before.clone(),
+            false => {
+                self.before.clone()
+                    + &self.template_start
+                    + &self._inside
+                    + &self.template_end
+                    + &self.after
+            }
+        }
+    }
+
+    /// The error box is wrapped in comments, so it can be removed again by the next run
+    fn error_box(&self, error: &str) -> String {
+        let message = format!("<nowiki>{}</nowiki>", error.replace("</nowiki>", ""));
+        let error_box = self
+            .list
+            .get_error_template()
+            .replace("$MESSAGE$", &message);
+        format!("<!--listeria-error-->{}<!--/listeria-error-->", error_box)
+    }
+
+    /// Only shown with `warnings=show`
+    fn warnings_box(&self) -> String {
+        if !self.list.show_warnings() || self.list.warnings().is_empty() {
+            return String::new();
         }
+        let message: Vec<String> = self.list.warnings().iter().map(|w| w.to_string()).collect();
+        let message = format!("<nowiki>{}</nowiki>", message.join("; ").replace("</nowiki>", ""));
+        self.list
+            .get_warning_template()
+            .replace("$MESSAGE$", &message)
+            + "\n"
+    }
+
+    fn remove_error_box(text: &str) -> String {
+        lazy_static! {
+            static ref RE_ERROR_BOX: Regex =
+                Regex::new(r#"(?s)<!--listeria-error-->.*?<!--/listeria-error-->\n?"#)
+                    .expect("RE_ERROR_BOX does not parse");
+        }
+        RE_ERROR_BOX.replace_all(text, "").to_string()
     }
 
     pub async fn process(&mut self) -> Result<(), String> {
-        match self.is_just_text {
-            true => Ok(()),
-            false => self.list.process().await,
+        if self.is_just_text {
+            return Ok(());
+        }
+        match self.list.process().await {
+            Ok(()) => Ok(()),
+            Err(e) => match self.on_error {
+                OnErrorMode::Fail => Err(e),
+                _ => {
+                    self.error = Some(e);
+                    Ok(())
+                }
+            },
         }
     }
 
-    pub fn is_just_text(&self) -> bool {
-        self.is_just_text
+    /// Changes between the list currently on the page, and the new one
+    pub fn table_diff(&self) -> Option<TableDiff> {
+        if self.is_just_text || self.error.is_some() {
+            return None;
+        }
+        let new_inside = self.new_inside().ok()?;
+        Some(TableDiff::new(&self._inside, &new_inside))
     }
 
-    fn get_template_end(text: String) -> Option<usize> {
-        let mut pos: usize = 0;
-        let mut curly_braces_open: usize = 2;
-        let tv = text.as_bytes();
-        while pos < tv.len() && curly_braces_open > 0 {
-            match tv[pos] as char {
-                '{' => curly_braces_open += 1,
-                '}' => curly_braces_open -= 1,
-                _ => {}
-            }
-            pos += 1;
+    /// True if the new list only differs from the one on the page in volatile content, or in row order for unsorted lists
+    /// The list has only volatile changes, and the text around it stays the same
+    pub fn is_semantically_unchanged(&self) -> bool {
+        if self.is_just_text {
+            return true;
         }
-        if curly_braces_open == 0 {
-            Some(pos)
-        } else {
-            None
+        if self.error.is_some() || self.template_end_as_written() != self.template_end {
+            return false;
+        }
+        let new_inside = match self.new_inside() {
+            Ok(new_inside) => self.warnings_box() + &new_inside,
+            Err(_) => return false,
+        };
+        let ignore_order = matches!(self.list.template_params().sort, SortMode::None);
+        is_semantically_equal(&self._inside, &new_inside, ignore_order)
+    }
+
+    /// Snapshot of the list after a successful run
+    pub fn snapshot(&self) -> Option<ListSnapshot> {
+        match self.is_just_text || self.error.is_some() {
+            true => None,
+            false => Some(ListSnapshot::new_from_list(&self.list)),
+        }
+    }
+
+    /// Rows shown on the page itself; for paginated lists, also the rows on each subpage
+    pub fn number_of_rows(&self) -> usize {
+        if self.is_just_text || self.is_tabbed_data() {
+            return 0;
+        }
+        let rows = self.list.results().len();
+        match self.rows_per_page() {
+            Some(rows_per_page) => rows.min(rows_per_page),
+            None => rows,
+        }
+    }
+
+    /// Has `paginate=`, whether or not the list currently needs more than one page
+    pub fn is_paginated(&self) -> bool {
+        !self.is_just_text
+            && self.error.is_none()
+            && !self.is_tabbed_data()
+            && self.list.template_params().paginate.is_some()
+    }
+
+    pub fn is_tabbed_data(&self) -> bool {
+        !self.is_just_text && self.list.template_params().tabbed_data
+    }
+
+    /// Writes the results to the tabbed data page on Commons; returns true if the data has changed
+    pub async fn write_tabbed_data(&self, commons_api: &mut Api) -> Result<bool, String> {
+        if !self.is_tabbed_data() || self.error.is_some() {
+            return Ok(false);
         }
+        let mut renderer = RendererTabbedData::new();
+        let tabbed_data_json = renderer.as_tabbed_data(&self.list);
+        renderer
+            .write_tabbed_data(tabbed_data_json, commons_api, &self.list, self.list_number)
+            .await
+    }
+
+    /// Paginated lists keep all rows, but spread them over more pages
+    pub fn truncate_rows(&mut self, max_rows: usize) {
+        match self.is_paginated() && max_rows > 0 {
+            true => self.max_rows_per_page = Some(max_rows),
+            false => self.list.truncate_results(max_rows),
+        }
+    }
+
+    /// Error of a list that failed, but is reported on the page
+    pub fn error(&self) -> &Option<String> {
+        &self.error
+    }
+
+    pub fn warnings(&self) -> &Vec<ValidationWarning> {
+        self.list.warnings()
+    }
+
+    pub fn is_just_text(&self) -> bool {
+        self.is_just_text
+    }
+
+    /// Position after the closing braces of a template, with `text` starting inside the template
+    pub fn get_template_end(text: String) -> Option<usize> {
+        find_template_end(&text)
     }
 }
@@ -0,0 +1,181 @@
+use crate::column::ColumnType;
+use crate::listeria_list::ListeriaList;
+use crate::table_diff::normalize_volatile;
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// How an item changed between two snapshots of a list
+#[derive(Debug, Clone, PartialEq)]
+pub enum ListChangeType {
+    Added,
+    Removed,
+    Changed,
+}
+
+impl ListChangeType {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::Added => "ADDED",
+            Self::Removed => "REMOVED",
+            Self::Changed => "CHANGED",
+        }
+    }
+
+    pub fn new_from_str(s: &str) -> Option<Self> {
+        match s {
+            "ADDED" => Some(Self::Added),
+            "REMOVED" => Some(Self::Removed),
+            "CHANGED" => Some(Self::Changed),
+            _ => None,
+        }
+    }
+}
+
+/// Compact record of a list after a successful run: entity IDs, and a hash of the rendered cells of each row
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ListSnapshot {
+    rows: HashMap<String, String>, // Entity ID => cell hash
+}
+
+impl ListSnapshot {
+    pub fn new_from_list(list: &ListeriaList) -> Self {
+        let mut rows = HashMap::new();
+        for (rownum, row) in list.results().iter().enumerate() {
+            let cells: Vec<String> = row
+                .cells()
+                .iter()
+                .enumerate()
+                .filter(|(colnum, _cell)| match list.column(*colnum) {
+                    Some(column) => column.obj != ColumnType::Number, // Row numbers change whenever rows are added
+                    None => false,
+                })
+                .map(|(colnum, cell)| cell.as_wikitext(list, rownum, colnum))
+                .collect();
+            let hash = Self::row_hash(&cells);
+            // With one_row_per_item=no, an entity can have several rows
+            let entry = rows.entry(row.entity_id().to_owned()).or_insert_with(String::new);
+            *entry = match entry.is_empty() {
+                true => hash,
+                false => format!("{:x}", md5::compute(format!("{}{}", entry, hash))),
+            };
+        }
+        Self { rows }
+    }
+
+    /// Hash of the rendered cells of a row, without volatile content like reference names
+    fn row_hash(cells: &[String]) -> String {
+        let cells: Vec<String> = cells.iter().map(|cell| normalize_volatile(cell)).collect();
+        format!("{:x}", md5::compute(cells.join("\t")))
+    }
+
+    pub fn new_from_json(j: &Value) -> Option<Self> {
+        let rows = j
+            .as_object()?
+            .iter()
+            .filter_map(|(k, v)| Some((k.to_owned(), v.as_str()?.to_string())))
+            .collect();
+        Some(Self { rows })
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!(self.rows)
+    }
+
+    pub fn len(&self) -> usize {
+        self.rows.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    /// Changes from `previous` to this snapshot, sorted by entity ID
+    pub fn changes_since(&self, previous: &ListSnapshot) -> Vec<(String, ListChangeType)> {
+        let mut ret: Vec<(String, ListChangeType)> = vec![];
+        for (entity_id, hash) in &self.rows {
+            match previous.rows.get(entity_id) {
+                Some(previous_hash) if previous_hash == hash => {}
+                Some(_) => ret.push((entity_id.to_owned(), ListChangeType::Changed)),
+                None => ret.push((entity_id.to_owned(), ListChangeType::Added)),
+            }
+        }
+        for entity_id in previous.rows.keys() {
+            if !self.rows.contains_key(entity_id) {
+                ret.push((entity_id.to_owned(), ListChangeType::Removed));
+            }
+        }
+        ret.sort_by(|a, b| a.0.cmp(&b.0));
+        ret
+    }
+}
+
+/// A change to a list, as recorded in the `list_change` table
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListChange {
+    pub list_index: usize,
+    pub entity_id: String,
+    pub change: ListChangeType,
+    pub timestamp: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(j: Value) -> ListSnapshot {
+        ListSnapshot::new_from_json(&j).unwrap()
+    }
+
+    #[test]
+    fn test_snapshot_json_round_trip() {
+        let s1 = snapshot(json!({"Q1":"aaa","Q2":"bbb"}));
+        assert_eq!(s1.len(), 2);
+        let s2 = ListSnapshot::new_from_json(&s1.to_json()).unwrap();
+        assert_eq!(s1, s2);
+        let s3: Value = serde_json::from_str(&s1.to_json().to_string()).unwrap();
+        assert_eq!(ListSnapshot::new_from_json(&s3).unwrap(), s1);
+        assert!(ListSnapshot::new_from_json(&json!([])).is_none());
+        assert!(snapshot(json!({})).is_empty());
+    }
+
+    #[test]
+    fn test_snapshot_changes_since() {
+        let previous = snapshot(json!({"Q1":"aaa","Q2":"bbb","Q3":"ccc"}));
+        let current = snapshot(json!({"Q1":"aaa","Q2":"xxx","Q4":"ddd"}));
+        assert_eq!(
+            current.changes_since(&previous),
+            vec![
+                ("Q2".to_string(), ListChangeType::Changed),
+                ("Q3".to_string(), ListChangeType::Removed),
+                ("Q4".to_string(), ListChangeType::Added),
+            ]
+        );
+        assert!(current.changes_since(&current).is_empty());
+    }
+
+    #[test]
+    fn test_row_hash_ignores_ref_names() {
+        let cells = |ref_name: &str| {
+            vec![
+                "[[Douglas Adams]]".to_string(),
+                format!("1952-03-11<ref name='ref_{}'>x</ref>", ref_name),
+            ]
+        };
+        assert_eq!(ListSnapshot::row_hash(&cells("1a2b")), ListSnapshot::row_hash(&cells("3c4d")));
+        let mut changed = cells("1a2b");
+        changed[1] = "1952-03-12<ref name='ref_1a2b'>x</ref>".to_string();
+        assert_ne!(ListSnapshot::row_hash(&cells("1a2b")), ListSnapshot::row_hash(&changed));
+    }
+
+    #[test]
+    fn test_change_type_strings() {
+        for change in [
+            ListChangeType::Added,
+            ListChangeType::Removed,
+            ListChangeType::Changed,
+        ] {
+            assert_eq!(ListChangeType::new_from_str(change.as_str()), Some(change));
+        }
+        assert_eq!(ListChangeType::new_from_str("MOVED"), None);
+    }
+}
@@ -2,6 +2,9 @@ use tokio::sync::Mutex;
 use chrono::{DateTime, Utc};
 use crate::configuration::Configuration;
 use crate::listeria_page::ListeriaPage;
+use crate::list_snapshot::{ListChange, ListChangeType, ListSnapshot};
+use crate::metrics::Metrics;
+use crate::validation::{LintIssue, LintSeverity};
 use mysql_async as my;
 use mysql_async::from_row;
 use mysql_async::prelude::*;
@@ -9,8 +12,11 @@ use serde_json::Value;
 use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::sync::RwLock;
+use tracing::{error, info, instrument};
 use wikibase::mediawiki::api::Api;
 
+const CLAIMED_BY_OTHER_INSTANCE: &str = "was claimed by another instance";
+
 #[derive(Debug, Clone, Default)]
 pub struct PageToProcess {
     pub id: u64,
@@ -41,6 +47,7 @@ pub struct WikiPageResult {
     pub page: String,
     pub result: String,
     pub message: String,
+    pub snapshots: Vec<(usize, ListSnapshot)>, // Lists after a successful run, by list index
 }
 
 unsafe impl Send for WikiPageResult {}
@@ -52,6 +59,7 @@ impl WikiPageResult {
             page: page.to_string(),
             result: result.to_string(),
             message,
+            snapshots: vec![],
         }
     }
 }
@@ -61,18 +69,21 @@ pub struct ListeriaBotWiki {
     wiki: String,
     api: Arc<RwLock<Api>>,
     config: Arc<Configuration>,
+    metrics: Arc<Metrics>,
 }
 
 impl ListeriaBotWiki {
-    pub fn new(wiki: &str, api: Arc<RwLock<Api>>, config: Arc<Configuration>) -> Self {
-        println!("Creating bot for {}", wiki);
+    pub fn new(wiki: &str, api: Arc<RwLock<Api>>, config: Arc<Configuration>, metrics: Arc<Metrics>) -> Self {
+        info!(wiki, "creating bot for wiki");
         Self {
             wiki: wiki.to_string(),
             api,
             config,
+            metrics,
         }
     }
 
+    #[instrument(skip(self), fields(wiki = %self.wiki))]
     pub async fn process_page(&self, page: &str) -> WikiPageResult {
         let mut listeria_page =
             match ListeriaPage::new(self.config.clone(), self.api.clone(), page.to_owned()).await {
@@ -86,6 +97,7 @@ impl ListeriaBotWiki {
                     )
                 }
             };
+        listeria_page.set_metrics(self.metrics.clone());
         match listeria_page.run().await {
             Ok(_) => {}
             Err(e) => return WikiPageResult::new(&self.wiki, page, "FAIL", e),
@@ -94,7 +106,19 @@ impl ListeriaBotWiki {
             Ok(x) => x,
             Err(e) => return WikiPageResult::new(&self.wiki, page, "FAIL", e),
         };
-        WikiPageResult::new(&self.wiki, page, "OK", "".to_string())
+        let list_errors = listeria_page.list_errors();
+        if !list_errors.is_empty() {
+            // Reported on the page, but still a failure
+            return WikiPageResult::new(&self.wiki, page, "FAIL", list_errors.join("; "));
+        }
+        let warnings: Vec<String> = listeria_page
+            .warnings()
+            .iter()
+            .map(|w| w.to_string())
+            .collect();
+        let mut ret = WikiPageResult::new(&self.wiki, page, "OK", warnings.join("; "));
+        ret.snapshots = listeria_page.snapshots();
+        ret
     }
 }
 
@@ -105,6 +129,8 @@ pub struct ListeriaBot {
     pool: mysql_async::Pool,
     site_matrix: Value,
     bot_per_wiki: Arc<Mutex<HashMap<String, ListeriaBotWiki>>>,
+    instance_id: String,
+    metrics: Arc<Metrics>,
 }
 
 impl ListeriaBot {
@@ -151,15 +177,48 @@ impl ListeriaBot {
             .get_query_api_json(&params)
             .await
             .map_err(|e| e.to_string())?;
+
+        let instance_id = match config.instance_id() {
+            Some(id) => id.to_owned(),
+            None => Self::generate_instance_id(),
+        };
+        info!(instance_id = %instance_id, "bot instance started");
+
         Ok(Self {
             config: Arc::new(config),
             wiki_apis: Arc::new(Mutex::new(HashMap::new())),
             pool: mysql_async::Pool::new(opts),
             site_matrix,
             bot_per_wiki: Arc::new(Mutex::new(HashMap::new())),
+            instance_id,
+            metrics: Arc::new(Metrics::new()),
         })
     }
 
+    /// Generates a (reasonably) unique ID for this bot process, used to own RUNNING leases
+    fn generate_instance_id() -> String {
+        let host = std::env::var("HOSTNAME").unwrap_or_else(|_| "unknown".to_string());
+        let now: DateTime<Utc> = Utc::now();
+        format!("{}:{}:{}", host, std::process::id(), now.timestamp())
+    }
+
+    pub fn instance_id(&self) -> &String {
+        &self.instance_id
+    }
+
+    pub fn config(&self) -> Arc<Configuration> {
+        self.config.clone()
+    }
+
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    fn timestamp_seconds_ago(seconds: u64) -> String {
+        let then: DateTime<Utc> = Utc::now() - chrono::Duration::seconds(seconds as i64);
+        then.format("%Y%m%d%H%M%S").to_string()
+    }
+
     async fn create_bot_for_wiki(&self, wiki: &str) -> Option<ListeriaBotWiki> {
         if let Some(bot) = self.bot_per_wiki.lock().await.get(wiki) {
             return Some(bot.to_owned())
@@ -167,7 +226,7 @@ impl ListeriaBot {
         let mw_api = match self.get_or_create_wiki_api(&wiki).await {
             Ok(mw_api) => mw_api,
             Err(e) => {
-                eprintln!("{}",e);
+                error!(wiki, "{}", e);
                 return None;
             }
         };
@@ -179,7 +238,7 @@ impl ListeriaBot {
             return Some(bot.to_owned())
         }
 
-        let bot = ListeriaBotWiki::new(&wiki, mw_api, self.config.clone());
+        let bot = ListeriaBotWiki::new(&wiki, mw_api, self.config.clone(), self.metrics.clone());
         bpw.insert(wiki.to_string(), bot.clone());
         return Some(bot);
     }
@@ -251,12 +310,73 @@ impl ListeriaBot {
             ))
     }
 
-    pub async fn reset_running(&self) -> Result<(),String> {
-        let sql = "UPDATE pagestatus SET status='OK' WHERE status='RUNNING'";
-        let _ = self.pool.get_conn().await.map_err(|e| e.to_string())?.exec_iter(sql, ()).await;
+    /*
+    RUNNING leases require these columns:
+    ALTER TABLE `pagestatus` ADD `running_instance` VARCHAR(255) NULL DEFAULT NULL, ADD `running_heartbeat` VARCHAR(14) NULL DEFAULT NULL;
+    */
+
+    /// Resets RUNNING pages whose lease has expired, that is, whose owning instance has not sent a heartbeat recently.
+    /// RUNNING pages of other, live instances are left alone.
+    pub async fn reclaim_stale_running(&self) -> Result<(), String> {
+        let cutoff = Self::timestamp_seconds_ago(self.config.running_lease_seconds());
+        let sql = "UPDATE `pagestatus` SET `status`='OK',`running_instance`=NULL,`running_heartbeat`=NULL WHERE `status`='RUNNING' AND (`running_heartbeat` IS NULL OR `running_heartbeat`<:cutoff)";
+        self.pool
+            .get_conn()
+            .await
+            .map_err(|e| e.to_string())?
+            .exec_drop(sql, params! {"cutoff" => cutoff})
+            .await
+            .map_err(|e| format!("ListeriaBot::reclaim_stale_running: SQL query error: {:?}", e))?;
+        Ok(())
+    }
+
+    /// Renews the lease on all pages currently RUNNING on this instance
+    pub async fn heartbeat(&self) -> Result<(), String> {
+        let now: DateTime<Utc> = Utc::now();
+        let params = params! {
+            "timestamp" => now.format("%Y%m%d%H%M%S").to_string(),
+            "instance" => self.instance_id.to_owned(),
+        };
+        let sql = "UPDATE `pagestatus` SET `running_heartbeat`=:timestamp WHERE `status`='RUNNING' AND `running_instance`=:instance";
+        self.pool
+            .get_conn()
+            .await
+            .map_err(|e| e.to_string())?
+            .exec_drop(sql, params)
+            .await
+            .map_err(|e| format!("ListeriaBot::heartbeat: SQL query error: {:?}", e))?;
+        Ok(())
+    }
+
+    /// Releases all pages still RUNNING on this instance, eg on shutdown
+    pub async fn release_running(&self) -> Result<(), String> {
+        let sql = "UPDATE `pagestatus` SET `status`='OK',`running_instance`=NULL,`running_heartbeat`=NULL WHERE `status`='RUNNING' AND `running_instance`=:instance";
+        self.pool
+            .get_conn()
+            .await
+            .map_err(|e| e.to_string())?
+            .exec_drop(sql, params! {"instance" => self.instance_id.to_owned()})
+            .await
+            .map_err(|e| format!("ListeriaBot::release_running: SQL query error: {:?}", e))?;
+        Ok(())
+    }
+
+    /// Updates the queue depth metric with the number of pages waiting to be processed
+    pub async fn update_queue_depth(&self) -> Result<(), String> {
+        let sql = "SELECT count(*) FROM pagestatus,wikis WHERE pagestatus.wiki=wikis.id AND wikis.status='ACTIVE' AND pagestatus.status!='RUNNING'";
+        let queue_depth = self
+            .pool
+            .get_conn()
+            .await
+            .map_err(|e| e.to_string())?
+            .exec_first::<u64, _, _>(sql, ())
+            .await
+            .map_err(|e| format!("ListeriaBot::update_queue_depth: SQL query error: {:?}", e))?
+            .unwrap_or(0);
+        self.metrics.set_queue_depth(queue_depth);
         Ok(())
     }
-  
+
     /// Returns a page to be processed. 
     pub async fn prepare_next_single_page(&self) -> Result<PageToProcess, String> {
         // Gets the first 1000 pages (by timestamp), then randomly picks one
@@ -276,11 +396,39 @@ impl ListeriaBot {
             .await
             .map_err(|e| format!("ListeriaBot::prepare_next_single_page: SQL query error[2]: {:?}",e))?
             .pop()
-            .ok_or(format!("!!"))?;
-        self.update_page_status(&page.title,&page.wiki,"RUNNING","PREPARING").await?;
+            .ok_or("ListeriaBot::prepare_next_single_page: no pages to process".to_string())?;
+        self.claim_page(&page).await?;
         Ok(page)
     }
 
+    /// Sets the page to RUNNING, owned by this instance.
+    /// Fails if another instance has claimed the page in the meantime.
+    async fn claim_page(&self, page: &PageToProcess) -> Result<(), String> {
+        let now: DateTime<Utc> = Utc::now();
+        let params = params! {
+            "id" => page.id,
+            "timestamp" => now.format("%Y%m%d%H%M%S").to_string(),
+            "instance" => self.instance_id.to_owned(),
+        };
+        let sql = "UPDATE `pagestatus` SET `status`='RUNNING',`message`='PREPARING',`timestamp`=:timestamp,`bot_version`=2,`running_instance`=:instance,`running_heartbeat`=:timestamp WHERE `id`=:id AND `status`!='RUNNING'";
+        let mut conn = self.pool.get_conn().await.map_err(|e| e.to_string())?;
+        conn.exec_drop(sql, params)
+            .await
+            .map_err(|e| format!("ListeriaBot::claim_page: SQL query error: {:?}", e))?;
+        if conn.affected_rows() == 0 {
+            return Err(format!(
+                "ListeriaBot::claim_page: {}:{} {}",
+                &page.wiki, &page.title, CLAIMED_BY_OTHER_INSTANCE
+            ));
+        }
+        Ok(())
+    }
+
+    /// The error is from `prepare_next_single_page`, because another instance got the page first
+    pub fn is_claim_conflict(error: &str) -> bool {
+        error.ends_with(CLAIMED_BY_OTHER_INSTANCE)
+    }
+
     pub async fn run_single_bot(&self, page: PageToProcess ) -> Result<(), String> {
         let bot = match self.create_bot_for_wiki(&page.wiki).await {
             Some(bot) => bot.to_owned(),
@@ -290,10 +438,109 @@ impl ListeriaBot {
             }
         };
         let wpr = bot.process_page(&page.title).await;
+        self.metrics.page_processed(&wpr.wiki, &wpr.result);
         self.update_page_status(&wpr.page, &wpr.wiki, &wpr.result, &wpr.message).await?;
+        if wpr.result == "OK" {
+            if let Err(e) = self.record_snapshots(page.id, &wpr.snapshots).await {
+                error!("{}", &e);
+            }
+        }
+        Ok(())
+    }
+
+    /*
+    List history requires these tables:
+    CREATE TABLE `list_snapshot` (`pagestatus_id` INT UNSIGNED NOT NULL, `list_index` INT UNSIGNED NOT NULL, `timestamp` VARCHAR(14) NOT NULL, `snapshot` MEDIUMTEXT NOT NULL, PRIMARY KEY (`pagestatus_id`,`list_index`));
+    CREATE TABLE `list_change` (`id` INT UNSIGNED NOT NULL AUTO_INCREMENT PRIMARY KEY, `pagestatus_id` INT UNSIGNED NOT NULL, `list_index` INT UNSIGNED NOT NULL, `entity_id` VARCHAR(32) NOT NULL, `change_type` ENUM('ADDED','REMOVED','CHANGED') NOT NULL, `timestamp` VARCHAR(14) NOT NULL, KEY (`pagestatus_id`,`timestamp`));
+    */
+
+    /// Stores the new list snapshots, and the changes since the previous ones.
+    /// The first snapshot of a list is stored without changes.
+    async fn record_snapshots(
+        &self,
+        pagestatus_id: u64,
+        snapshots: &[(usize, ListSnapshot)],
+    ) -> Result<(), String> {
+        let timestamp = Utc::now().format("%Y%m%d%H%M%S").to_string();
+        let mut conn = self.pool.get_conn().await.map_err(|e| e.to_string())?;
+        for (list_index, snapshot) in snapshots {
+            let list_index = *list_index as u64;
+            let sql = "SELECT `snapshot` FROM `list_snapshot` WHERE `pagestatus_id`=:id AND `list_index`=:list_index";
+            let previous = conn
+                .exec_first::<String, _, _>(sql, params! {"id" => pagestatus_id, "list_index" => list_index})
+                .await
+                .map_err(|e| format!("ListeriaBot::record_snapshots: SQL query error[1]: {:?}", e))?
+                .and_then(|s| serde_json::from_str::<Value>(&s).ok())
+                .and_then(|j| ListSnapshot::new_from_json(&j));
+
+            if let Some(previous) = previous {
+                let changes: Vec<my::Params> = snapshot
+                    .changes_since(&previous)
+                    .iter()
+                    .map(|(entity_id, change)| {
+                        params! {
+                            "id" => pagestatus_id,
+                            "list_index" => list_index,
+                            "entity_id" => entity_id,
+                            "change_type" => change.as_str(),
+                            "timestamp" => timestamp.to_owned(),
+                        }
+                    })
+                    .collect();
+                if !changes.is_empty() {
+                    let sql = "INSERT INTO `list_change` (`pagestatus_id`,`list_index`,`entity_id`,`change_type`,`timestamp`) VALUES (:id,:list_index,:entity_id,:change_type,:timestamp)";
+                    conn.exec_batch(sql, changes)
+                        .await
+                        .map_err(|e| format!("ListeriaBot::record_snapshots: SQL query error[2]: {:?}", e))?;
+                }
+            }
+
+            let sql = "REPLACE INTO `list_snapshot` (`pagestatus_id`,`list_index`,`timestamp`,`snapshot`) VALUES (:id,:list_index,:timestamp,:snapshot)";
+            let params = params! {
+                "id" => pagestatus_id,
+                "list_index" => list_index,
+                "timestamp" => timestamp.to_owned(),
+                "snapshot" => snapshot.to_json().to_string(),
+            };
+            conn.exec_drop(sql, params)
+                .await
+                .map_err(|e| format!("ListeriaBot::record_snapshots: SQL query error[3]: {:?}", e))?;
+        }
         Ok(())
     }
 
+    /// Items that entered, left, or changed in the lists on a page since `since` (format "%Y%m%d%H%M%S")
+    pub async fn get_list_changes(
+        &self,
+        wiki: &str,
+        page: &str,
+        since: &str,
+    ) -> Result<Vec<ListChange>, String> {
+        let sql = "SELECT `list_change`.`list_index`,`list_change`.`entity_id`,`list_change`.`change_type`,`list_change`.`timestamp` FROM `list_change`,`pagestatus`,`wikis` WHERE `list_change`.`pagestatus_id`=`pagestatus`.`id` AND `pagestatus`.`wiki`=`wikis`.`id` AND `wikis`.`name`=:wiki AND `pagestatus`.`page`=:page AND `list_change`.`timestamp`>=:since ORDER BY `list_change`.`timestamp`,`list_change`.`list_index`,`list_change`.`entity_id`";
+        let rows = self
+            .pool
+            .get_conn()
+            .await
+            .map_err(|e| e.to_string())?
+            .exec_iter(sql, params! {"wiki" => wiki, "page" => page, "since" => since})
+            .await
+            .map_err(|e| format!("ListeriaBot::get_list_changes: SQL query error[1]: {:?}", e))?
+            .map_and_drop(from_row::<(u64, String, String, String)>)
+            .await
+            .map_err(|e| format!("ListeriaBot::get_list_changes: SQL query error[2]: {:?}", e))?;
+        Ok(rows
+            .into_iter()
+            .filter_map(|(list_index, entity_id, change_type, timestamp)| {
+                Some(ListChange {
+                    list_index: list_index as usize,
+                    entity_id,
+                    change: ListChangeType::new_from_str(&change_type)?,
+                    timestamp,
+                })
+            })
+            .collect())
+    }
+
     async fn update_page_status(
         &self,
         page: &str,
@@ -303,15 +550,22 @@ impl ListeriaBot {
     ) -> Result<(), String> {
         let now: DateTime<Utc> = Utc::now();
         let timestamp = now.format("%Y%m%d%H%M%S").to_string();
-        println!("{wiki}:{page} : {status}");
+        info!(wiki, page, status, "page status");
+        let (instance, heartbeat) = if status == "RUNNING" {
+            (Some(self.instance_id.to_owned()), Some(timestamp.to_owned()))
+        } else {
+            (None, None)
+        };
         let params = params! {
             "wiki" => wiki,
             "page" => page,
             "timestamp" => timestamp,
             "status" => status,
             "message" => message, //format!("V2:{}",&message),
+            "instance" => instance,
+            "heartbeat" => heartbeat,
         };
-        let sql = "UPDATE `pagestatus` SET `status`=:status,`message`=:message,`timestamp`=:timestamp,`bot_version`=2 WHERE `wiki`=(SELECT id FROM `wikis` WHERE `name`=:wiki) AND `page`=:page".to_string() ;
+        let sql = "UPDATE `pagestatus` SET `status`=:status,`message`=:message,`timestamp`=:timestamp,`bot_version`=2,`running_instance`=:instance,`running_heartbeat`=:heartbeat WHERE `wiki`=(SELECT id FROM `wikis` WHERE `name`=:wiki) AND `page`=:page".to_string() ;
         self.pool.get_conn().await.map_err(|e| e.to_string())?
             .exec_iter(sql.as_str(), params)
             .await
@@ -322,6 +576,48 @@ impl ListeriaBot {
         Ok(())
     }
 
+    /// Checks the list definitions on all pages in `pagestatus` (optionally, for one wiki only), without running any queries.
+    /// Returns (wiki,page,issues) for pages with issues.
+    pub async fn lint_pages(
+        &self,
+        wiki: Option<&str>,
+    ) -> Result<Vec<(String, String, Vec<LintIssue>)>, String> {
+        let sql = "SELECT wikis.name,pagestatus.page FROM pagestatus,wikis WHERE pagestatus.wiki=wikis.id AND wikis.status='ACTIVE' AND (:wiki IS NULL OR wikis.name=:wiki) ORDER BY wikis.name,pagestatus.page";
+        let pages = self
+            .pool
+            .get_conn()
+            .await
+            .map_err(|e| e.to_string())?
+            .exec_iter(sql, params! {"wiki" => wiki})
+            .await
+            .map_err(|e| format!("ListeriaBot::lint_pages: SQL query error[1]: {:?}", e))?
+            .map_and_drop(from_row::<(String, String)>)
+            .await
+            .map_err(|e| format!("ListeriaBot::lint_pages: SQL query error[2]: {:?}", e))?;
+
+        let mut ret = vec![];
+        for (wiki, page) in pages {
+            let issues = match self.lint_page(&wiki, &page).await {
+                Ok(issues) => issues,
+                Err(e) => vec![LintIssue {
+                    list: 0,
+                    severity: LintSeverity::Error,
+                    message: e,
+                }],
+            };
+            if !issues.is_empty() {
+                ret.push((wiki, page, issues));
+            }
+        }
+        Ok(ret)
+    }
+
+    async fn lint_page(&self, wiki: &str, page: &str) -> Result<Vec<LintIssue>, String> {
+        let mw_api = self.get_or_create_wiki_api(wiki).await?;
+        let listeria_page = ListeriaPage::new(self.config.clone(), mw_api, page.to_owned()).await?;
+        listeria_page.lint().await
+    }
+
     async fn create_wiki_api(&self, wiki: &str) -> Result<Arc<RwLock<Api>>, String> {
         let api_url = format!("{}/w/api.php", self.get_server_url_for_wiki(wiki)?);
         let mut mw_api = wikibase::mediawiki::api::Api::new(&api_url)
@@ -1,7 +1,9 @@
+use crate::configuration::{CitationTemplate, WikibaseSettings};
 use crate::entity_container_wrapper::*;
 use crate::result_cell::*;
 use crate::result_cell_part::ResultCellPart;
 use crate::result_row::ResultRow;
+use crate::validation::{validate_template, ValidationWarning};
 use crate::{
     Column, ColumnType, LinksType, PageParams, ReferencesParameter, SectionType, SortMode,
     SortOrder, SparqlValue, Template, TemplateParams,
@@ -10,11 +12,16 @@ use serde_json::Value;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::sync::Arc;
+use std::time::Instant;
 use tokio::sync::RwLock;
 use wikibase::entity::*;
 use wikibase::mediawiki::api::Api;
 use wikibase::snak::SnakDataType;
 use futures::future::join_all;
+use tracing::{debug, instrument};
+
+/// Number of entities per SPARQL query when looking up regions
+const REGION_BATCH_SIZE: usize = 100;
 
 #[derive(Debug, Clone)]
 pub struct ListeriaList {
@@ -30,9 +37,11 @@ pub struct ListeriaList {
     local_page_cache: HashMap<String, bool>,
     section_id_to_name: HashMap<usize, String>,
     wb_api: Arc<Api>,
+    wikibase_settings: WikibaseSettings,
     language: String,
     reference_ids: Arc<std::sync::RwLock<HashSet<String>>>,
-    profiling:bool,
+    warnings: Vec<ValidationWarning>,
+    truncated: bool,
 }
 
 impl ListeriaList {
@@ -40,6 +49,8 @@ impl ListeriaList {
         let wb_api = page_params.wb_api.clone();
         let mut template = template;
         template.fix_values();
+        let mut ecw = EntityContainerWrapper::new();
+        ecw.set_entity_cache(page_params.config.entity_cache());
         Self {
             page_params: page_params.clone(),
             template,
@@ -47,37 +58,30 @@ impl ListeriaList {
             params: TemplateParams::new(),
             sparql_rows: vec![],
             sparql_main_variable: None,
-            ecw: EntityContainerWrapper::new(),
+            ecw,
             results: vec![],
             shadow_files: vec![],
             local_page_cache: HashMap::new(),
             section_id_to_name: HashMap::new(),
             wb_api,
+            wikibase_settings: WikibaseSettings::default(),
             language: page_params.language.to_string(),
             reference_ids: Arc::new(std::sync::RwLock::new(HashSet::new())),
-            profiling:false,
-        }
-    }
-
-    fn profile(&self, msg:&str) {
-        if self.profiling {
-            println!("{}",msg);
+            warnings: vec![],
+            truncated: false,
         }
     }
 
+    #[instrument(skip_all)]
     pub async fn process(&mut self) -> Result<(), String> {
-        self.profile("START list::process");
         self.process_template().await?;
-        self.profile("AFTER list::process process_template");
         self.run_query().await?;
-        self.profile("AFTER list::process run_query");
         self.load_entities().await?;
-        self.profile("AFTER list::process load_entities");
         self.generate_results().await?;
-        self.profile("AFTER list::process generate_results");
         self.process_results().await?;
-        self.profile("AFTER list::process process_results");
-        self.profile("END list::process");
+        if let Some(metrics) = self.page_params.metrics() {
+            metrics.entities_loaded(self.ecw.number_of_entities_loaded());
+        }
         Ok(())
     }
 
@@ -109,8 +113,10 @@ impl ListeriaList {
         self.section_id_to_name.get(&id)
     }
 
+    #[instrument(level = "debug", skip_all)]
     pub async fn process_template(&mut self) -> Result<(), String> {
         let template = self.template.clone();
+        self.warnings = validate_template(&template);
         match template.params.get("columns") {
             Some(columns) => {
                 columns.split(',').for_each(|part| {
@@ -134,6 +140,10 @@ impl ListeriaList {
             Some(api) => api.clone(),
             None => return Err(format!("No wikibase setup configured for '{}'", &wikibase)),
         };
+        self.wikibase_settings = self
+            .page_params
+            .config
+            .get_wikibase_settings(&wikibase.to_lowercase());
 
         Ok(())
     }
@@ -242,23 +252,52 @@ impl ListeriaList {
     }
 
     pub async fn run_sparql_query(&self, sparql: &str) -> Result<Value, String> {
-        let endpoint = match self
-            .wb_api
-            .get_site_info_string("general", "wikibase-sparql")
-        {
-            Ok(endpoint) => {
-                // SPARQL service given by site
-                endpoint
-            }
-            _ => {
-                // Override SPARQL service (hardcoded for Commons)
-                "https://wcqs-beta.wmflabs.org/sparql"
+        let config = &self.page_params.config;
+        let wikibase = self.params.wikibase.to_lowercase();
+        let endpoint = match config.get_sparql_endpoint(&wikibase) {
+            Some(endpoint) => {
+                // SPARQL service configured for this wikibase
+                endpoint.to_owned()
             }
+            None => match self
+                .wb_api
+                .get_site_info_string("general", "wikibase-sparql")
+            {
+                Ok(url) => {
+                    // SPARQL service given by site
+                    config.get_default_sparql_endpoint(url)?
+                }
+                _ => {
+                    // Override SPARQL service (hardcoded for Commons)
+                    config.get_default_sparql_endpoint("https://wcqs-beta.wmflabs.org/sparql")?
+                }
+            },
         };
-        match self.wb_api.sparql_query_endpoint(sparql, endpoint).await {
-            Ok(j) => Ok(j),
-            Err(e) => Err(format!("run_sparql_query: {:?}", &e)),
+        let endpoint = config.with_wbapi_credentials(&wikibase, endpoint);
+
+        let sparql_cache = config.sparql_cache();
+        if let Some(cache) = sparql_cache {
+            if let Some(result) = cache.get(endpoint.url(), sparql, self.params.cache) {
+                debug!("Using cached SPARQL result from {}", endpoint.url());
+                return Ok(result);
+            }
+        }
+
+        let start_time = Instant::now();
+        let result = endpoint
+            .query_with_retry(sparql)
+            .await
+            .map_err(|e| e.to_string());
+        if let Some(metrics) = self.page_params.metrics() {
+            metrics.observe_sparql_query(start_time.elapsed());
         }
+
+        if let (Some(cache), Ok(result)) = (sparql_cache, &result) {
+            if let Err(e) = cache.set(endpoint.url(), sparql, result) {
+                debug!("Could not cache SPARQL result: {}", e);
+            }
+        }
+        result
     }
 
     async fn expand_sparql_templates(&self, sparql: &mut String) -> Result<(), String> {
@@ -286,6 +325,7 @@ impl ListeriaList {
         Ok(())
     }
 
+    #[instrument(level = "debug", skip_all)]
     pub async fn run_query(&mut self) -> Result<(), String> {
         let mut sparql = match self.template.params.get("sparql") {
             Some(s) => s,
@@ -306,11 +346,9 @@ impl ListeriaList {
             }
         }
 
-        self.profile("BEGIN run_query: run_sparql_query");
         let j = self.run_sparql_query(&sparql).await?;
-        self.profile("END run_query: run_sparql_query");
         if self.page_params.simulate {
-            println!("{}\n{}\n", &sparql, &j);
+            debug!(sparql = %sparql, results = %j, "simulated SPARQL query");
         }
         self.parse_sparql(j)
     }
@@ -347,7 +385,10 @@ impl ListeriaList {
             let mut row: HashMap<String, SparqlValue> = HashMap::new();
             if let Some(bo) = b.as_object() {
                 for (k, v) in bo.iter() {
-                    match SparqlValue::new_from_json(&v) {
+                    match SparqlValue::new_from_json_with_concept_uri(
+                        &v,
+                        self.wikibase_settings.concept_uri(),
+                    ) {
                         Some(v2) => row.insert(k.to_owned(), v2),
                         None => {
                             return Err(format!("Can't parse SPARQL value: {} => {:?}", &k, &v))
@@ -360,9 +401,70 @@ impl ListeriaList {
             }
             self.sparql_rows.push(row);
         }
+        self.check_row_limit()
+    }
+
+    /// Fails the list if it would have more rows than allowed, or truncates it if so configured.
+    /// With `one_row_per_item`, the SPARQL rows of one item make a single list row.
+    fn check_row_limit(&mut self) -> Result<(), String> {
+        let max_rows = match self.page_params.config.max_rows_per_list() {
+            Some(max_rows) => max_rows,
+            None => return Ok(()),
+        };
+        let varname = match &self.sparql_main_variable {
+            Some(varname) => varname.to_owned(),
+            None => return Ok(()), // The list fails later on anyway
+        };
+        // The list row each SPARQL row ends up in; rows without an item are not shown
+        let mut item_rows: HashMap<String, usize> = HashMap::new();
+        let mut keep_sparql_rows = vec![];
+        let mut list_rows = 0;
+        for row in self.sparql_rows.iter() {
+            let id = match row.get(&varname) {
+                Some(SparqlValue::Entity(id)) => id,
+                _ => {
+                    keep_sparql_rows.push(false);
+                    continue;
+                }
+            };
+            let list_row = match self.params.one_row_per_item {
+                true => {
+                    let next_row = item_rows.len() + 1;
+                    *item_rows.entry(id.to_owned()).or_insert(next_row)
+                }
+                false => list_rows + 1,
+            };
+            list_rows = list_rows.max(list_row);
+            keep_sparql_rows.push(list_row <= max_rows);
+        }
+        if list_rows <= max_rows {
+            return Ok(());
+        }
+        if !self.page_params.config.truncate_oversized_lists() {
+            return Err(format!(
+                "The list would have {} rows, more than the maximum of {} rows per list",
+                list_rows, max_rows
+            ));
+        }
+        let mut keep = keep_sparql_rows.into_iter();
+        self.sparql_rows.retain(|_| keep.next().unwrap_or(false));
+        self.truncated = true;
         Ok(())
     }
 
+    /// Removes all result rows after the first `max_rows`
+    pub fn truncate_results(&mut self, max_rows: usize) {
+        if self.results.len() > max_rows {
+            self.results.truncate(max_rows);
+            self.truncated = true;
+        }
+    }
+
+    pub fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+
+    #[instrument(level = "debug", skip_all)]
     pub async fn load_entities(&mut self) -> Result<(), String> {
         // Any columns that require entities to be loaded?
         // TODO also force if self.links is redlinks etc.
@@ -482,6 +584,7 @@ impl ListeriaList {
         }
     }
 
+    #[instrument(level = "debug", skip_all)]
     pub async fn generate_results(&mut self) -> Result<(), String> {
         let varname = self.get_var_name()?;
         let mut results: Vec<ResultRow> = vec![];
@@ -507,9 +610,7 @@ impl ListeriaList {
                 for (id,sparql_rows) in &tmp_rows {
                     futures.push(self.ecw.get_result_row(&id, &sparql_rows, &self));
                 }
-                self.profile("BEGIN generate_results join_all");
                 let tmp_results = join_all(futures).await;
-                self.profile("END generate_results join_all");
                 results = tmp_results
                     .iter()
                     .cloned()
@@ -531,6 +632,7 @@ impl ListeriaList {
         Ok(())
     }
 
+    #[instrument(level = "debug", skip_all)]
     fn process_items_to_local_links(&mut self) -> Result<(), String> {
         // Try to change items to local link
         // TODO get rid of clone()
@@ -545,12 +647,14 @@ impl ListeriaList {
         Ok(())
     }
 
+    #[instrument(level = "debug", skip_all)]
     fn process_excess_files(&mut self) {
         self.results.iter_mut().for_each(|row| {
             row.remove_excess_files();
         });
     }
 
+    #[instrument(level = "debug", skip_all)]
     async fn process_remove_shadow_files(&mut self) -> Result<(), String> {
         if !self
             .page_params
@@ -653,6 +757,7 @@ impl ListeriaList {
         Ok(())
     }
 
+    #[instrument(level = "debug", skip_all)]
     fn process_redlinks_only(&mut self) -> Result<(), String> {
         if *self.get_links_type() != LinksType::RedOnly {
             return Ok(());
@@ -677,6 +782,7 @@ impl ListeriaList {
         Ok(())
     }
 
+    #[instrument(level = "debug", skip_all)]
     async fn process_redlinks(&mut self) -> Result<(), String> {
         if *self.get_links_type() != LinksType::RedOnly && *self.get_links_type() != LinksType::Red
         {
@@ -722,6 +828,7 @@ impl ListeriaList {
         Ok(())
     }
 
+    #[instrument(level = "debug", skip_all)]
     async fn process_sort_results(&mut self) -> Result<(), String> {
         let sortkeys: Vec<String>;
         let mut datatype = SnakDataType::String; // Default
@@ -788,6 +895,7 @@ impl ListeriaList {
         Ok(())
     }
 
+    #[instrument(level = "debug", skip_all)]
     pub async fn process_assign_sections(&mut self) -> Result<(), String> {
         // TODO all SectionType options
         let section_property = match &self.params.section {
@@ -829,7 +937,7 @@ impl ListeriaList {
         valid_section_names.sort();
 
         let misc_id = valid_section_names.len();
-        valid_section_names.push("Misc".to_string());
+        valid_section_names.push(self.message("misc_section"));
 
         // TODO skip if no/one section?
 
@@ -860,34 +968,73 @@ impl ListeriaList {
         Ok(())
     }
 
-    async fn get_region_for_entity_id(&self, entity_id: &str) -> Option<String> {
-        let sparql = format!(
-            "SELECT ?q ?x {{ wd:{} wdt:P131* ?q . ?q wdt:P300 ?x }}",
-            entity_id
-        );
-        let j = self.run_sparql_query(&sparql).await.ok()?;
-        match j["results"]["bindings"].as_array() {
-            Some(a) => {
-                let mut region = String::new();
-                a.iter().for_each(|b| {
+    /// Looks up the ISO 3166-2 region (P300) of the entities, via their P131 chain.
+    /// Uses batched SPARQL queries; results are cached across lists and pages, per wikibase.
+    async fn get_regions_for_entity_ids(&self, entity_ids: &[String]) -> HashMap<String, String> {
+        let config = &self.page_params.config;
+        let mut ret = HashMap::new();
+        let wikibase = self.params.wikibase.to_lowercase();
+        let mut to_query = vec![];
+        for entity_id in entity_ids {
+            match config.get_cached_region(&format!("{}:{}", wikibase, entity_id)) {
+                Some(Some(region)) => {
+                    ret.insert(entity_id.to_owned(), region);
+                }
+                Some(None) => {} // Known to have no region
+                None => to_query.push(entity_id.to_owned()),
+            }
+        }
+
+        for chunk in to_query.chunks(REGION_BATCH_SIZE) {
+            let settings = &self.wikibase_settings;
+            let values: Vec<String> = chunk
+                .iter()
+                .map(|id| format!("<{}{}>", settings.entity_uri(), id))
+                .collect();
+            let direct = settings.direct_property_uri();
+            let sparql = format!(
+                "SELECT ?item ?x {{ VALUES ?item {{ {} }} ?item <{}{}>* ?q . ?q <{}{}> ?x }}",
+                values.join(" "),
+                direct,
+                settings.property("P131"),
+                direct,
+                settings.property("P300")
+            );
+            let j = match self.run_sparql_query(&sparql).await {
+                Ok(j) => j,
+                Err(_) => continue, // Do not cache failures
+            };
+            let mut chunk_regions: HashMap<String, String> = HashMap::new();
+            if let Some(a) = j["results"]["bindings"].as_array() {
+                for b in a {
                     match b["x"]["type"].as_str() {
                         Some("literal") => {}
-                        _ => return,
+                        _ => continue,
                     }
+                    let entity_id = match SparqlValue::new_from_json_with_concept_uri(
+                        &b["item"],
+                        self.wikibase_settings.concept_uri(),
+                    ) {
+                        Some(SparqlValue::Entity(id)) => id,
+                        _ => continue,
+                    };
                     if let Some(r) = b["x"]["value"].as_str() {
+                        let region = chunk_regions.entry(entity_id).or_insert_with(String::new);
                         if r.len() > region.len() {
-                            region = r.to_string();
+                            *region = r.to_string();
                         }
                     }
-                });
-                if region.is_empty() {
-                    None
-                } else {
-                    Some(region)
                 }
             }
-            None => None,
+            for entity_id in chunk {
+                let region = chunk_regions.remove(entity_id);
+                config.set_cached_region(&format!("{}:{}", wikibase, entity_id), region.to_owned());
+                if let Some(region) = region {
+                    ret.insert(entity_id.to_owned(), region);
+                }
+            }
         }
+        ret
     }
 
     fn do_get_regions(&self) -> bool {
@@ -897,6 +1044,7 @@ impl ListeriaList {
             .contains(self.wiki())
     }
 
+    #[instrument(level = "debug", skip_all)]
     pub async fn process_regions(&mut self) -> Result<(), String> {
         if !self.do_get_regions() {
             return Ok(());
@@ -914,12 +1062,8 @@ impl ListeriaList {
             });
         });
 
-        let mut entity_id2region = HashMap::new();
-        for entity_id in entity_ids {
-            if let Some(region) = self.get_region_for_entity_id(&entity_id).await {
-                entity_id2region.insert(entity_id, region);
-            }
-        }
+        let entity_ids: Vec<String> = entity_ids.into_iter().collect();
+        let entity_id2region = self.get_regions_for_entity_ids(&entity_ids).await;
 
         for row in self.results.iter_mut() {
             let the_region = match entity_id2region.get(row.entity_id()) {
@@ -938,6 +1082,7 @@ impl ListeriaList {
         Ok(())
     }
 
+    #[instrument(level = "debug", skip_all)]
     async fn process_reference_items(&mut self) -> Result<(), String> {
         let mut items_to_load: Vec<String> = vec![];
         for row in self.results.iter_mut() {
@@ -946,10 +1091,7 @@ impl ListeriaList {
                     match &part_with_reference.references {
                         Some(references) => {
                             for reference in references.iter() {
-                                match &reference.stated_in {
-                                    Some(stated_in) => items_to_load.push(stated_in.to_string()),
-                                    None => {}
-                                }
+                                items_to_load.extend(reference.entities_to_load());
                             }
                         }
                         None => {}
@@ -965,6 +1107,7 @@ impl ListeriaList {
         Ok(())
     }
 
+    #[instrument(level = "debug", skip_all)]
     async fn fix_local_links(&mut self) -> Result<(), String> {
         // Set the is_category flag
         let mw_api = self.mw_api();
@@ -992,31 +1135,19 @@ impl ListeriaList {
         Ok(())
     }
 
+    #[instrument(level = "debug", skip_all)]
     pub async fn process_results(&mut self) -> Result<(), String> {
-        self.profile("START list::process_results");
         self.gather_and_load_items().await?;
-        self.profile("AFTER list::process_results gather_and_load_items");
         self.process_redlinks_only()?;
-        self.profile("AFTER list::process_results process_redlinks_only");
         self.process_items_to_local_links()?;
-        self.profile("AFTER list::process_results process_items_to_local_links");
         self.process_redlinks().await?;
-        self.profile("AFTER list::process_results process_redlinks");
         self.process_remove_shadow_files().await?;
-        self.profile("AFTER list::process_results process_remove_shadow_files");
         self.process_excess_files();
-        self.profile("AFTER list::process_results process_excess_files");
         self.process_reference_items().await?;
-        self.profile("AFTER list::process_results process_reference_items");
         self.process_sort_results().await?;
-        self.profile("AFTER list::process_results process_sort_results");
         self.process_assign_sections().await?;
-        self.profile("AFTER list::process_results process_assign_sections");
         self.process_regions().await?;
-        self.profile("AFTER list::process_results process_regions");
         self.fix_local_links().await?;
-        self.profile("AFTER list::process_results fix_local_links");
-        self.profile("END list::process_results");
         Ok(())
     }
 
@@ -1032,6 +1163,16 @@ impl ListeriaList {
         &self.params.row_template
     }
 
+    /// Pass raw values, section name and row number to the row template
+    pub fn row_template_raw(&self) -> bool {
+        self.params.row_template_raw
+    }
+
+    /// SPARQL variables to pass to the row template, in addition to the columns
+    pub fn row_template_vars(&self) -> &Vec<String> {
+        &self.params.row_template_vars
+    }
+
     pub fn get_reference_parameter(&self) -> &ReferencesParameter {
         &self.params.references
     }
@@ -1078,6 +1219,7 @@ impl ListeriaList {
         self.gather_items_for_property(&prop)
     }
 
+    #[instrument(level = "debug", skip_all)]
     async fn gather_and_load_items(&mut self) -> Result<(), String> {
         // Gather items to load
         let mut entities_to_load: Vec<String> = vec![];
@@ -1133,6 +1275,64 @@ impl ListeriaList {
         &self.page_params.wiki
     }
 
+    pub fn get_error_template(&self) -> String {
+        self.page_params.config.get_error_template(self.wiki())
+    }
+
+    pub fn suppress_imported_references(&self) -> bool {
+        self.page_params.config.suppress_imported_references()
+    }
+
+    /// None means plain links
+    pub fn get_citation_template(&self) -> Option<CitationTemplate> {
+        self.page_params
+            .config
+            .get_citation_template(self.wiki(), &self.language)
+    }
+
+    /// Bot-generated text in the list language
+    pub fn message(&self, key: &str) -> String {
+        self.page_params.config.messages().get(&self.language, key)
+    }
+
+    pub fn format_message(&self, key: &str, params: &[&str]) -> String {
+        self.page_params
+            .config
+            .messages()
+            .format(&self.language, key, params)
+    }
+
+    pub fn get_tabbed_data_template(&self) -> String {
+        self.page_params.config.get_tabbed_data_template(self.wiki())
+    }
+
+    /// The end template, for pages where it is missing
+    pub fn get_end_template(&self) -> String {
+        let title = self
+            .page_params
+            .config
+            .get_local_template_title_end(self.wiki())
+            .unwrap_or_else(|_| "Wikidata list end".to_string());
+        format!("{{{{{}}}}}", title)
+    }
+
+    pub fn get_warning_template(&self) -> String {
+        self.page_params.config.get_warning_template(self.wiki())
+    }
+
+    /// Problems with the template parameters
+    pub fn warnings(&self) -> &Vec<ValidationWarning> {
+        &self.warnings
+    }
+
+    pub fn show_warnings(&self) -> bool {
+        self.template
+            .params
+            .get("warnings")
+            .map(|s| s.trim().to_lowercase())
+            == Some("show".to_string())
+    }
+
     pub fn page_title(&self) -> &String {
         &self.page_params.page
     }
@@ -1178,12 +1378,21 @@ impl ListeriaList {
         }
     }
 
+    /// True if the page is on the wiki that hosts the list's Wikibase
     pub fn is_wikidatawiki(&self) -> bool {
-        self.page_params.wiki == "wikidatawiki"
+        self.page_params.wiki == self.wikibase_settings.home_wiki()
+    }
+
+    pub fn wikibase_settings(&self) -> &WikibaseSettings {
+        &self.wikibase_settings
     }
 
     pub fn get_item_wiki_target(&self, entity_id: &str) -> String {
-        let prefix = if self.is_wikidatawiki() { "" } else { ":d:" };
+        let prefix = if self.is_wikidatawiki() {
+            ""
+        } else {
+            self.wikibase_settings.interwiki_prefix()
+        };
         if let Some(first_char) = entity_id.chars().next() {
             if first_char == 'p' || first_char == 'P' {
                 return format!("{}Property:{}", prefix, entity_id);
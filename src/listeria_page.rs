@@ -1,7 +1,11 @@
+use crate::list_snapshot::ListSnapshot;
+use crate::table_diff::{is_semantically_equal, TableDiff};
+use crate::validation::{lint_wikitext, LintIssue, ValidationWarning};
 use crate::*;
 use futures::future::try_join_all;
 use std::collections::HashMap;
 use std::sync::Arc;
+use tracing::{info, info_span, instrument, Instrument};
 use wikibase::mediawiki::api::Api;
 
 /* TODO
@@ -63,6 +67,13 @@ impl ListeriaPage {
         }
     }
 
+    /// Metrics will be recorded for this page; needs to be set before `run()`
+    pub fn set_metrics(&mut self, metrics: Arc<Metrics>) {
+        if let Some(pp) = Arc::get_mut(&mut self.page_params) {
+            pp.metrics = Some(metrics);
+        }
+    }
+
     pub fn page_params(&self) -> Arc<PageParams> {
         self.page_params.clone()
     }
@@ -90,13 +101,14 @@ impl ListeriaPage {
         }
     }
 
+    #[instrument(name = "page", skip_all, fields(wiki = %self.page_params.wiki, title = %self.page_params.page))]
     pub async fn run(&mut self) -> Result<(), String> {
         self.check_namespace().await?;
         self.elements = self.load_page().await?;
 
         let mut promises = Vec::new();
-        for element in &mut self.elements {
-            promises.push(element.process());
+        for (list_index, element) in self.elements.iter_mut().enumerate() {
+            promises.push(element.process().instrument(info_span!("list", list_index)));
         }
         try_join_all(promises).await?;
         Ok(())
@@ -106,9 +118,14 @@ impl ListeriaPage {
         let mut text = self.load_page_as("wikitext").await?;
         let mut ret = vec![];
         let mut again: bool = true;
+        let mut list_number = 0;
         while again {
                 let mut element = match PageElement::new_from_text(&text, &self) {
-                Some(pe) => pe,
+                Some(mut pe) => {
+                    list_number += 1;
+                    pe.set_list_number(list_number);
+                    pe
+                }
                 None => {
                     again = false;
                     PageElement::new_just_text(&text, self)
@@ -156,6 +173,12 @@ impl ListeriaPage {
         }
     }
 
+    /// Checks the list definitions on the page, without running any queries
+    pub async fn lint(&self) -> Result<Vec<LintIssue>, String> {
+        let text = self.load_page_as("wikitext").await?;
+        lint_wikitext(&text, self)
+    }
+
     pub fn as_wikitext(&self) -> Result<Vec<String>, String> {
         let mut ret: Vec<String> = vec![];
         for element in &self.elements {
@@ -170,14 +193,45 @@ impl ListeriaPage {
         &self.elements
     }
 
-    async fn save_wikitext_to_page(&self, title: &str, wikitext: &str) -> Result<(), String> {
+    /// Problems with the template parameters of all lists on the page
+    pub fn warnings(&self) -> Vec<ValidationWarning> {
+        self.elements
+            .iter()
+            .flat_map(|element| element.warnings().to_owned())
+            .collect()
+    }
+
+    /// Snapshots of the lists on the page, with their index (counting lists only)
+    pub fn snapshots(&self) -> Vec<(usize, ListSnapshot)> {
+        self.elements
+            .iter()
+            .filter(|element| !element.is_just_text())
+            .enumerate()
+            .filter_map(|(list_index, element)| Some((list_index, element.snapshot()?)))
+            .collect()
+    }
+
+    /// Errors of lists that were reported on the page instead of failing it
+    pub fn list_errors(&self) -> Vec<String> {
+        self.elements
+            .iter()
+            .filter_map(|element| element.error().to_owned())
+            .collect()
+    }
+
+    async fn save_wikitext_to_page(
+        &self,
+        title: &str,
+        wikitext: &str,
+        summary: &str,
+    ) -> Result<(), String> {
         let mut api = self.page_params.mw_api.write().await;
         let token = api.get_edit_token().await.map_err(|e| e.to_string())?;
         let params: HashMap<String, String> = vec![
             ("action", "edit"),
             ("title", title),
             ("text", wikitext),
-            ("summary", "Wikidata list updated [V2]"),
+            ("summary", summary),
             ("token", &token),
             ("bot","1"),
         ]
@@ -197,35 +251,226 @@ impl ListeriaPage {
         }
     }
 
+    #[instrument(name = "page", skip_all, fields(wiki = %self.page_params.wiki, title = %self.page_params.page))]
     pub async fn update_source_page(&mut self) -> Result<bool, String> {
+        self.update_tabbed_data().await?;
         let renderer = RendererWikitext::new();
         let mut edited = false;
         let old_wikitext = self.load_page_as("wikitext").await?;
-        let new_wikitext = renderer.get_new_wikitext(&old_wikitext, self)?; // Safe
+        let new_wikitext = self.check_page_size(&renderer, &old_wikitext)?;
         match new_wikitext {
             Some(new_wikitext) => {
-                if old_wikitext != new_wikitext {
-                    self.save_wikitext_to_page(&self.page_params.page, &new_wikitext)
+                if old_wikitext != new_wikitext && self.is_semantically_unchanged(&old_wikitext) {
+                    info!("only volatile content changed, not saving");
+                } else if old_wikitext != new_wikitext {
+                    let summary = self.edit_summary();
+                    self.save_wikitext_to_page(&self.page_params.page, &new_wikitext, &summary)
                         .await?;
                     edited = true;
                 }
             }
-            None => {
-                if self.data_has_changed {
-                    self.purge_page().await?;
+            None => {}
+        }
+        if !edited && self.data_has_changed {
+            // The page shows tabbed data that has changed
+            self.purge_page().await?;
+        }
+        if self.update_subpages().await? {
+            edited = true;
+        }
+
+        if let Some(metrics) = self.page_params.metrics() {
+            metrics.page_saved(edited);
+        }
+        info!(edited, "page updated");
+        Ok(edited)
+    }
+
+    /// Renders the new page, and checks it and the subpages of paginated lists against the configured size limit.
+    /// If truncation is allowed, the list with the most rows on the oversized page is shortened until all pages fit;
+    /// paginated lists get fewer rows per page instead.
+    fn check_page_size(
+        &mut self,
+        renderer: &RendererWikitext,
+        old_wikitext: &str,
+    ) -> Result<Option<String>, String> {
+        let max_bytes = self.config().max_page_bytes();
+        let truncate = self.config().truncate_oversized_lists();
+        loop {
+            let new_wikitext = match renderer.get_new_wikitext(old_wikitext, self)? {
+                Some(new_wikitext) => new_wikitext,
+                None => return Ok(None),
+            };
+            let (element_index, size) = match new_wikitext.len() > max_bytes {
+                true => {
+                    let element_index = self
+                        .elements
+                        .iter()
+                        .enumerate()
+                        .max_by_key(|(_, element)| element.number_of_rows())
+                        .map(|(element_index, _)| element_index);
+                    (element_index, new_wikitext.len())
                 }
+                false => match self.oversized_subpage(max_bytes)? {
+                    Some((element_index, size)) => (Some(element_index), size),
+                    None => return Ok(Some(new_wikitext)),
+                },
+            };
+            let too_large = format!(
+                "The new page would be {} bytes, more than the maximum of {} bytes",
+                size, max_bytes
+            );
+            if !truncate {
+                return Err(too_large);
+            }
+            match element_index.and_then(|element_index| self.elements.get_mut(element_index)) {
+                Some(element) if element.number_of_rows() > 1 => {
+                    // Shorten in proportion to the excess; at least one row per round, but never to an empty list
+                    let rows = element.number_of_rows();
+                    let keep = (rows * max_bytes / size).clamp(1, rows - 1);
+                    element.truncate_rows(keep);
+                }
+                Some(element) if element.number_of_rows() == 1 => {
+                    return Err(format!("{}, even with a single row in the list", too_large))
+                }
+                _ => return Err(too_large),
+            }
+        }
+    }
+
+    /// The first list with a subpage larger than `max_bytes`, and the size of its largest subpage
+    fn oversized_subpage(&self, max_bytes: usize) -> Result<Option<(usize, usize)>, String> {
+        for (element_index, element) in self.elements.iter().enumerate() {
+            let size = element
+                .subpages()?
+                .iter()
+                .map(|(_, wikitext)| wikitext.len())
+                .max()
+                .unwrap_or(0);
+            if size > max_bytes {
+                return Ok(Some((element_index, size)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Writes the results of `tabbed_data` lists to their data pages on Commons
+    async fn update_tabbed_data(&mut self) -> Result<(), String> {
+        if !self.elements.iter().any(|element| element.is_tabbed_data()) {
+            return Ok(());
+        }
+        if self.page_params.simulate {
+            info!("SIMULATING: writing tabbed data");
+            return Ok(());
+        }
+        let mut commons_api = self.config().get_commons_api().await?;
+        for element in &self.elements {
+            if element.write_tabbed_data(&mut commons_api).await? {
+                self.data_has_changed = true;
             }
         }
+        Ok(())
+    }
 
+    /// Saves the subpages of paginated lists, where they have changed.
+    /// Subpages left over from when a list had more pages are redirected to the page.
+    async fn update_subpages(&self) -> Result<bool, String> {
+        let mut edited = false;
+        let summary = format!(
+            "Wikidata list updated [V2]: page of [[{}]]",
+            &self.page_params.page
+        );
+        for element in &self.elements {
+            for (title, wikitext) in element.subpages()? {
+                let old_wikitext = self.load_wikitext(&title).await.unwrap_or_default();
+                if is_semantically_equal(&old_wikitext, &wikitext, false) {
+                    continue;
+                }
+                self.save_wikitext_to_page(&title, &wikitext, &summary)
+                    .await?;
+                edited = true;
+            }
+            if element.is_paginated() && self.redirect_stale_subpages(element, &summary).await? {
+                edited = true;
+            }
+        }
         Ok(edited)
     }
 
+    /// Redirects the subpages after the last page of the list, until one does not exist or is already redirected
+    async fn redirect_stale_subpages(&self, element: &PageElement, summary: &str) -> Result<bool, String> {
+        let redirect = format!("#REDIRECT [[{}]]", &self.page_params.page);
+        let mut edited = false;
+        let mut page_number = element.number_of_pages() + 1;
+        loop {
+            let title = element.subpage_title(page_number);
+            match self.load_wikitext(&title).await {
+                Ok(wikitext) if wikitext.trim() != redirect => {
+                    self.save_wikitext_to_page(&title, &redirect, summary)
+                        .await?;
+                    edited = true;
+                }
+                _ => return Ok(edited), // Does not exist, or was redirected before
+            }
+            page_number += 1;
+        }
+    }
+
+    /// Current wikitext of another page; error if it does not exist
+    async fn load_wikitext(&self, title: &str) -> Result<String, String> {
+        let params: HashMap<String, String> = vec![
+            ("action", "parse"),
+            ("prop", "wikitext"),
+            ("page", title),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        let result = self
+            .page_params
+            .mw_api
+            .read()
+            .await
+            .get_query_api_json(&params)
+            .await
+            .map_err(|e| e.to_string())?;
+        match result["parse"]["wikitext"]["*"].as_str() {
+            Some(ret) => Ok(ret.to_string()),
+            None => Err(format!("No wikitext for {}", title)),
+        }
+    }
+
+    /// Only the lists have changed, and only in volatile content.
+    /// The elements must add up to `old_wikitext`, so no change outside the lists gets lost.
+    fn is_semantically_unchanged(&self, old_wikitext: &str) -> bool {
+        let original_wikitext: String = self
+            .elements
+            .iter()
+            .map(|element| element.original_wikitext())
+            .collect();
+        original_wikitext == old_wikitext
+            && self
+                .elements
+                .iter()
+                .all(|element| element.is_semantically_unchanged())
+    }
+
+    /// eg "Wikidata list updated [V2]: +3 items, −1 item, 12 cells changed"
+    fn edit_summary(&self) -> String {
+        let mut diff = TableDiff::default();
+        self.elements
+            .iter()
+            .filter_map(|element| element.table_diff())
+            .for_each(|element_diff| diff.add(&element_diff));
+        match diff.is_empty() {
+            true => "Wikidata list updated [V2]".to_string(),
+            false => format!("Wikidata list updated [V2]: {}", diff.as_summary()),
+        }
+    }
+
     async fn purge_page(&self) -> Result<(), String> {
         if self.page_params.simulate {
-            println!(
-                "SIMULATING: purging [[{}]] on {}",
-                &self.page_params.page, self.page_params.wiki
-            );
+            info!("SIMULATING: purging page");
             return Ok(());
         }
         let params: HashMap<String, String> = vec![
@@ -0,0 +1,19 @@
+use std::env;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::EnvFilter;
+
+/// Log level filter from LISTERIA_LOG (eg "info,listeria=debug"), or `default_level` if that is not set.
+/// JSON output if LISTERIA_LOG_FORMAT=json. Logs go to stderr, so they do not mix with command output.
+pub fn init_logging(default_level: &str) {
+    let filter =
+        EnvFilter::try_from_env("LISTERIA_LOG").unwrap_or_else(|_| EnvFilter::new(default_level));
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_span_events(FmtSpan::CLOSE)
+        .with_writer(std::io::stderr);
+    if env::var("LISTERIA_LOG_FORMAT").map(|f| f == "json").unwrap_or(false) {
+        subscriber.json().init();
+    } else {
+        subscriber.init();
+    }
+}
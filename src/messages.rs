@@ -0,0 +1,103 @@
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Message files that are compiled into the bot
+const BUNDLED_MESSAGES: &[(&str, &str)] = &[
+    ("en", include_str!("../messages/en.json")),
+    ("de", include_str!("../messages/de.json")),
+];
+
+/// Text that the bot writes to pages, by language; `$1`, `$2`... are replaced with parameters
+#[derive(Debug, Clone)]
+pub struct Messages {
+    messages: HashMap<String, HashMap<String, String>>, // Language => key => message
+    default_language: String,
+}
+
+impl Default for Messages {
+    fn default() -> Self {
+        Self::new_from_json(&Value::Null, "en")
+    }
+}
+
+impl Messages {
+    /// The bundled messages, overridden by the ones in `j`, eg `{"de":{"misc_section":"Sonstiges"}}`
+    pub fn new_from_json(j: &Value, default_language: &str) -> Self {
+        let mut ret = Self {
+            messages: HashMap::new(),
+            default_language: default_language.to_lowercase(),
+        };
+        for (language, text) in BUNDLED_MESSAGES {
+            let bundled: Value = serde_json::from_str(text).expect("bundled messages do not parse");
+            ret.add_messages(language, &bundled);
+        }
+        if let Some(o) = j.as_object() {
+            for (language, messages) in o.iter() {
+                ret.add_messages(language, messages);
+            }
+        }
+        ret
+    }
+
+    fn add_messages(&mut self, language: &str, j: &Value) {
+        if let Some(o) = j.as_object() {
+            let messages = self.messages.entry(language.to_lowercase()).or_default();
+            for (key, message) in o.iter() {
+                if let Some(message) = message.as_str() {
+                    messages.insert(key.to_string(), message.to_string());
+                }
+            }
+        }
+    }
+
+    /// Tries the language, its base language (`de` for `de-at`), the default language, and English, in that order
+    pub fn get(&self, language: &str, key: &str) -> String {
+        let language = language.to_lowercase();
+        let base_language = language.split('-').next().unwrap_or_default().to_string();
+        let languages = [
+            language.to_owned(),
+            base_language,
+            self.default_language.to_owned(),
+            "en".to_string(),
+        ];
+        languages
+            .iter()
+            .find_map(|language| self.messages.get(language)?.get(key))
+            .map(|message| message.to_string())
+            .unwrap_or_else(|| format!("⧼{}⧽", key)) // Like MediaWiki does for missing messages
+    }
+
+    pub fn format(&self, language: &str, key: &str, params: &[&str]) -> String {
+        params
+            .iter()
+            .enumerate()
+            .rev() // $10 before $1
+            .fold(self.get(language, key), |message, (num, param)| {
+                message.replace(&format!("${}", num + 1), param)
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_messages_fallback() {
+        let j = json!({"de-at":{"misc_section":"Diverses"}});
+        let messages = Messages::new_from_json(&j, "en");
+        assert_eq!(messages.get("de-at", "misc_section"), "Diverses");
+        assert_eq!(messages.get("de-at", "no_value"), "Kein/unbekannter Wert");
+        assert_eq!(messages.get("de-at", "cite_web"), "cite web");
+        assert_eq!(messages.get("xx", "misc_section"), "Misc");
+        assert_eq!(messages.get("en", "no_such_message"), "⧼no_such_message⧽");
+    }
+
+    #[test]
+    fn test_messages_format() {
+        let messages = Messages::default();
+        assert_eq!(messages.format("en", "item_count", &["5"]), "&sum; 5 items.");
+        assert_eq!(messages.format("en", "decade", &["1990"]), "1990s");
+        assert_eq!(messages.format("de", "century", &["19"]), "19. Jahrhundert");
+    }
+}
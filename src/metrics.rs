@@ -0,0 +1,146 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Upper bounds (in seconds) of the SPARQL latency histogram buckets
+const SPARQL_LATENCY_BUCKETS: [f64; 8] = [0.1, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+
+#[derive(Debug, Clone, Default)]
+struct LatencyHistogram {
+    buckets: [u64; 8],
+    sum: f64,
+    count: u64,
+}
+
+impl LatencyHistogram {
+    fn observe(&mut self, seconds: f64) {
+        for (num, upper_bound) in SPARQL_LATENCY_BUCKETS.iter().enumerate() {
+            if seconds <= *upper_bound {
+                self.buckets[num] += 1;
+            }
+        }
+        self.sum += seconds;
+        self.count += 1;
+    }
+}
+
+/// Process-wide counters for the bot, rendered in the Prometheus text format
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pages_processed: Mutex<HashMap<(String, String), u64>>, // (wiki,status) => count
+    sparql_latency: Mutex<LatencyHistogram>,
+    entities_loaded: AtomicU64,
+    edits: AtomicU64,
+    no_edits: AtomicU64,
+    queue_depth: AtomicU64,
+    running: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn page_processed(&self, wiki: &str, status: &str) {
+        if let Ok(mut pages_processed) = self.pages_processed.lock() {
+            *pages_processed
+                .entry((wiki.to_string(), status.to_string()))
+                .or_insert(0) += 1;
+        }
+    }
+
+    pub fn observe_sparql_query(&self, duration: Duration) {
+        if let Ok(mut sparql_latency) = self.sparql_latency.lock() {
+            sparql_latency.observe(duration.as_secs_f64());
+        }
+    }
+
+    pub fn entities_loaded(&self, number: usize) {
+        self.entities_loaded
+            .fetch_add(number as u64, Ordering::Relaxed);
+    }
+
+    pub fn page_saved(&self, edited: bool) {
+        match edited {
+            true => self.edits.fetch_add(1, Ordering::Relaxed),
+            false => self.no_edits.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+
+    pub fn set_queue_depth(&self, queue_depth: u64) {
+        self.queue_depth.store(queue_depth, Ordering::Relaxed);
+    }
+
+    pub fn set_running(&self, running: usize) {
+        self.running.store(running as u64, Ordering::Relaxed);
+    }
+
+    pub fn render(&self) -> String {
+        let mut ret = String::new();
+
+        ret += "# HELP listeria_pages_processed_total Pages processed, by wiki and result status\n";
+        ret += "# TYPE listeria_pages_processed_total counter\n";
+        if let Ok(pages_processed) = self.pages_processed.lock() {
+            let mut keys: Vec<&(String, String)> = pages_processed.keys().collect();
+            keys.sort();
+            for key in keys {
+                ret += &format!(
+                    "listeria_pages_processed_total{{wiki=\"{}\",status=\"{}\"}} {}\n",
+                    key.0, key.1, pages_processed[key]
+                );
+            }
+        }
+
+        ret += "# HELP listeria_sparql_query_seconds SPARQL query latency\n";
+        ret += "# TYPE listeria_sparql_query_seconds histogram\n";
+        if let Ok(sparql_latency) = self.sparql_latency.lock() {
+            for (num, upper_bound) in SPARQL_LATENCY_BUCKETS.iter().enumerate() {
+                ret += &format!(
+                    "listeria_sparql_query_seconds_bucket{{le=\"{}\"}} {}\n",
+                    upper_bound, sparql_latency.buckets[num]
+                );
+            }
+            ret += &format!(
+                "listeria_sparql_query_seconds_bucket{{le=\"+Inf\"}} {}\n",
+                sparql_latency.count
+            );
+            ret += &format!("listeria_sparql_query_seconds_sum {}\n", sparql_latency.sum);
+            ret += &format!("listeria_sparql_query_seconds_count {}\n", sparql_latency.count);
+        }
+
+        ret += "# HELP listeria_entities_loaded_total Entities loaded from Wikibase\n";
+        ret += "# TYPE listeria_entities_loaded_total counter\n";
+        ret += &format!(
+            "listeria_entities_loaded_total {}\n",
+            self.entities_loaded.load(Ordering::Relaxed)
+        );
+
+        ret += "# HELP listeria_page_saves_total Pages checked for saving, by whether an edit was made\n";
+        ret += "# TYPE listeria_page_saves_total counter\n";
+        ret += &format!(
+            "listeria_page_saves_total{{result=\"edit\"}} {}\n",
+            self.edits.load(Ordering::Relaxed)
+        );
+        ret += &format!(
+            "listeria_page_saves_total{{result=\"noop\"}} {}\n",
+            self.no_edits.load(Ordering::Relaxed)
+        );
+
+        ret += "# HELP listeria_queue_depth Pages waiting to be processed\n";
+        ret += "# TYPE listeria_queue_depth gauge\n";
+        ret += &format!(
+            "listeria_queue_depth {}\n",
+            self.queue_depth.load(Ordering::Relaxed)
+        );
+
+        ret += "# HELP listeria_running_tasks Pages currently being processed\n";
+        ret += "# TYPE listeria_running_tasks gauge\n";
+        ret += &format!(
+            "listeria_running_tasks {}\n",
+            self.running.load(Ordering::Relaxed)
+        );
+
+        ret
+    }
+}
@@ -1,4 +1,5 @@
 use crate::listeria_list::ListeriaList;
+use regex::Regex;
 use std::sync::Arc;
 use std::sync::RwLock;
 
@@ -6,8 +7,17 @@ use std::sync::RwLock;
 pub struct Reference {
     pub url: Option<String>,
     pub title: Option<String>,
-    pub date: Option<String>,
+    pub date: Option<String>,      // Last access
     pub stated_in: Option<String>, // Item
+    pub author_items: Vec<String>,
+    pub author_names: Vec<String>,
+    pub publication_date: Option<String>,
+    pub archive_url: Option<String>,
+    pub archive_date: Option<String>,
+    pub imported_from: Option<String>,     // Item
+    pub imported_from_url: Option<String>, // Wikimedia import URL
+    pub pages: Option<String>,
+    pub external_id: Option<(String, String)>, // Property, ID
     md5: Arc<RwLock<String>>,
     wikitext_cache: Arc<RwLock<Option<String>>>,
 }
@@ -17,6 +27,15 @@ impl PartialEq for Reference {
             && self.title == other.title
             && self.date == other.date
             && self.stated_in == other.stated_in
+            && self.author_items == other.author_items
+            && self.author_names == other.author_names
+            && self.publication_date == other.publication_date
+            && self.archive_url == other.archive_url
+            && self.archive_date == other.archive_date
+            && self.imported_from == other.imported_from
+            && self.imported_from_url == other.imported_from_url
+            && self.pages == other.pages
+            && self.external_id == other.external_id
     }
 }
 
@@ -25,63 +44,79 @@ impl Reference {
         let mut ret = Self {
             ..Default::default()
         };
+        let mut other_language_title: Option<String> = None;
 
         for snak in snaks.iter() {
-            match snak.property() {
-                "P854" => {
+            let data_value = match snak.data_value() {
+                Some(dv) => dv.value().to_owned(),
+                None => continue,
+            };
+            match (snak.property(), data_value) {
+                ("P854", wikibase::Value::StringValue(url)) => {
                     // Reference URL
-                    if let Some(dv) = snak.data_value() {
-                        if let wikibase::Value::StringValue(url) = dv.value() {
-                            ret.url = Some(url.to_owned());
-                        }
-                    }
+                    ret.url = Some(url);
                 }
-                "P1476" => {
-                    // Title
-                    if let Some(dv) = snak.data_value() {
-                        if let wikibase::Value::MonoLingual(mlt) = dv.value() {
-                            if mlt.language() == language {
-                                ret.title = Some(mlt.text().to_owned());
-                            }
-                        }
+                ("P1476", wikibase::Value::MonoLingual(mlt)) => {
+                    // Title; in another language only if there is none in the page language
+                    if mlt.language() == language {
+                        ret.title = Some(mlt.text().to_owned());
+                    } else if other_language_title.is_none() {
+                        other_language_title = Some(mlt.text().to_owned());
                     }
                 }
-                "P813" => {
+                ("P813", wikibase::Value::Time(tv)) => {
                     // Timestamp/last access
-                    if let Some(dv) = snak.data_value() {
-                        if let wikibase::Value::Time(tv) = dv.value() {
-                            if let Some(pos) = tv.time().find('T') {
-                                let (date, _) = tv.time().split_at(pos);
-                                let mut date = date.replace('+', "").to_string();
-                                if *tv.precision() >= 11 { // Day
-                                     // Keep
-                                } else if *tv.precision() == 10 {
-                                    // Month
-                                    if let Some(pos) = date.rfind('-') {
-                                        date = date.split_at(pos).0.to_string();
-                                    }
-                                } else if *tv.precision() <= 9 {
-                                    // Year etc TODO century etc
-                                    if let Some(pos) = date.find('-') {
-                                        date = date.split_at(pos).0.to_string();
-                                    }
-                                }
-                                ret.date = Some(date);
-                            }
-                        }
-                    }
+                    ret.date = Self::reduce_date(&tv);
                 }
-                "P248" => {
+                ("P248", wikibase::Value::Entity(item)) => {
                     // Stated in
-                    if let Some(dv) = snak.data_value() {
-                        if let wikibase::Value::Entity(item) = dv.value() {
-                            ret.stated_in = Some(item.id().to_owned());
-                        }
+                    ret.stated_in = Some(item.id().to_owned());
+                }
+                ("P50", wikibase::Value::Entity(item)) => {
+                    // Author
+                    ret.author_items.push(item.id().to_owned());
+                }
+                ("P2093", wikibase::Value::StringValue(name)) => {
+                    // Author name string
+                    ret.author_names.push(name);
+                }
+                ("P577", wikibase::Value::Time(tv)) => {
+                    // Publication date
+                    ret.publication_date = Self::reduce_date(&tv);
+                }
+                ("P1065", wikibase::Value::StringValue(url)) => {
+                    // Archive URL
+                    ret.archive_url = Some(url);
+                }
+                ("P2960", wikibase::Value::Time(tv)) => {
+                    // Archive date
+                    ret.archive_date = Self::reduce_date(&tv);
+                }
+                ("P143", wikibase::Value::Entity(item)) => {
+                    // Imported from Wikimedia project
+                    ret.imported_from = Some(item.id().to_owned());
+                }
+                ("P4656", wikibase::Value::StringValue(url)) => {
+                    // Wikimedia import URL
+                    ret.imported_from_url = Some(url);
+                }
+                ("P304", wikibase::Value::StringValue(pages)) => {
+                    // Pages
+                    ret.pages = Some(pages);
+                }
+                (property, wikibase::Value::StringValue(id)) => {
+                    if matches!(snak.datatype(), wikibase::SnakDataType::ExternalId)
+                        && ret.external_id.is_none()
+                    {
+                        ret.external_id = Some((property.to_string(), id));
                     }
                 }
                 _ => {}
             }
         }
+        if ret.title.is_none() {
+            ret.title = other_language_title;
+        }
 
         if ret.is_empty() {
             None
@@ -90,8 +125,75 @@ impl Reference {
         }
     }
 
+    /// Date as YYYY-MM-DD, YYYY-MM, or YYYY, depending on the precision
+    fn reduce_date(tv: &wikibase::TimeValue) -> Option<String> {
+        let pos = tv.time().find('T')?;
+        let (date, _) = tv.time().split_at(pos);
+        let mut date = date.replace('+', "").to_string();
+        if *tv.precision() >= 11 { // Day
+             // Keep
+        } else if *tv.precision() == 10 {
+            // Month
+            if let Some(pos) = date.rfind('-') {
+                date = date.split_at(pos).0.to_string();
+            }
+        } else if *tv.precision() <= 9 {
+            // Year etc TODO century etc
+            if let Some(pos) = date.find('-') {
+                date = date.split_at(pos).0.to_string();
+            }
+        }
+        Some(date)
+    }
+
+    /// Only says that the statement was imported from Wikipedia (P143/P4656): no source besides Wikipedia URLs,
+    /// no "stated in", and no external ID
+    pub fn is_imported(&self) -> bool {
+        let urls: Vec<&String> = self.url.iter().chain(self.imported_from_url.iter()).collect();
+        (self.imported_from.is_some() || self.imported_from_url.is_some())
+            && self.stated_in.is_none()
+            && self.external_id.is_none()
+            && !urls.is_empty()
+            && urls.iter().all(|url| Self::is_wikipedia_url(url))
+    }
+
+    fn is_wikipedia_url(url: &str) -> bool {
+        lazy_static! {
+            static ref RE_WIKIPEDIA: Regex = Regex::new(r#"^(https?:)?//[a-z0-9-]+(\.m)?\.wikipedia\.org/"#)
+                .expect("RE_WIKIPEDIA does not parse");
+        }
+        RE_WIKIPEDIA.is_match(url)
+    }
+
+    pub fn has_url(&self) -> bool {
+        self.url.is_some() || self.archive_url.is_some()
+    }
+
+    /// Items and properties that are needed to render the reference
+    pub fn entities_to_load(&self) -> Vec<String> {
+        let mut ret: Vec<String> = self.author_items.to_owned();
+        ret.extend(self.stated_in.iter().cloned());
+        ret.extend(self.imported_from.iter().cloned());
+        ret.extend(self.external_id.iter().map(|(property, _id)| property.to_owned()));
+        ret
+    }
+
     fn is_empty(&self) -> bool {
-        self.url.is_none() && self.stated_in.is_none()
+        self.url.is_none() && self.stated_in.is_none() && self.external_id.is_none()
+    }
+
+    /// Author items as links, followed by author name strings
+    fn authors_as_wikitext(&self, list: &ListeriaList) -> Option<String> {
+        let authors: Vec<String> = self
+            .author_items
+            .iter()
+            .map(|item| list.get_item_link_with_fallback(item))
+            .chain(self.author_names.iter().cloned())
+            .collect();
+        match authors.is_empty() {
+            true => None,
+            false => Some(authors.join(", ")),
+        }
     }
 
     pub fn as_reference(&self, list: &ListeriaList) -> String {
@@ -123,30 +225,75 @@ impl Reference {
         }
         let mut s = String::new();
 
-        if self.title.is_some() && self.url.is_some() {
-            s += &format!(
-                "{{{{cite web|url={}|title={}",
-                self.url.as_ref().unwrap_or(&String::new()),
-                self.title.as_ref().unwrap_or(&String::new())
-            );
-            if let Some(stated_in) = &self.stated_in {
-                s += &format!("|website={}", list.get_item_link_with_fallback(stated_in));
-            }
-            if let Some(date) = &self.date {
-                s += &format!("|access-date={}", &date);
-            }
-            s += "}}";
-        } else if self.url.is_some() {
-            if let Some(x) = self.url.as_ref() {
-                s += x;
-            }
-        } else if self.stated_in.is_some() {
-            match &self.stated_in {
-                Some(q) => {
-                    s += &list.get_item_link_with_fallback(&q);
+        // Without a "stated in", an external ID can stand in for the URL, via the formatter URL of its property.
+        // With one, the reference is shown as the source, as before (eg GND: stated in Q36578, with P227).
+        let external_id_url = match self.stated_in {
+            Some(_) => None,
+            None => self
+                .external_id
+                .as_ref()
+                .and_then(|(property, id)| Some((list.ecw.external_id_url(property, id)?, id))),
+        };
+        let (url, title) = match (&self.url, &external_id_url) {
+            (Some(url), _) => (Some(url.to_owned()), self.title.to_owned()),
+            (None, Some((url, id))) => (
+                Some(url.to_owned()),
+                self.title.to_owned().or_else(|| Some(id.to_string())),
+            ),
+            (None, None) => (None, self.title.to_owned()),
+        };
+
+        if let (Some(url), Some(title)) = (&url, &title) {
+            let website = self
+                .stated_in
+                .as_ref()
+                .map(|stated_in| list.get_item_link_with_fallback(stated_in));
+            let authors = self.authors_as_wikitext(list);
+            match list.get_citation_template() {
+                Some(citation_template) => {
+                    s += "{{";
+                    s += citation_template.template();
+                    let fields = vec![
+                        ("url", Some(url.to_owned())),
+                        ("title", Some(title.to_owned())),
+                        ("author", authors),
+                        ("date", self.publication_date.to_owned()),
+                        ("website", website),
+                        ("pages", self.pages.to_owned()),
+                        ("access_date", self.date.to_owned()),
+                        ("archive_url", self.archive_url.to_owned()),
+                        ("archive_date", self.archive_date.to_owned()),
+                        (
+                            "imported_from",
+                            self.imported_from
+                                .as_ref()
+                                .map(|item| list.get_item_link_with_fallback(item)),
+                        ),
+                    ];
+                    for (field, value) in fields {
+                        if let (Some(param), Some(value)) = (citation_template.param(field), value) {
+                            s += &format!("|{}={}", param, value);
+                        }
+                    }
+                    s += "}}";
+                }
+                None => {
+                    let mut parts = vec![];
+                    parts.extend(authors);
+                    parts.push(format!("[{} {}]", url, title));
+                    parts.extend(website);
+                    parts.extend(self.publication_date.to_owned());
+                    parts.extend(self.pages.to_owned());
+                    if let Some(archive_url) = &self.archive_url {
+                        parts.push(format!("[{} archive]", archive_url));
+                    }
+                    s += &parts.join(", ");
                 }
-                None => {}
             }
+        } else if let Some(url) = &url {
+            s += url;
+        } else if let Some(q) = &self.stated_in {
+            s += &list.get_item_link_with_fallback(q);
         }
 
         match self.md5.write() {
@@ -165,3 +312,251 @@ impl Reference {
         self.as_wikitext(list)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ReferencesParameter;
+    use serde_json::Value;
+    use wikibase::entity::{Entity, EntityTrait};
+
+    fn snak(property: &str, datatype: &str, value: Value, value_type: &str) -> Value {
+        json!({
+            "snaktype": "value",
+            "property": property,
+            "datatype": datatype,
+            "datavalue": {"value": value, "type": value_type}
+        })
+    }
+
+    fn item_snak(property: &str, item: &str) -> Value {
+        let value = json!({"entity-type": "item", "numeric-id": item[1..].parse::<u64>().unwrap(), "id": item});
+        snak(property, "wikibase-item", value, "wikibase-entityid")
+    }
+
+    fn string_snak(property: &str, datatype: &str, s: &str) -> Value {
+        snak(property, datatype, json!(s), "string")
+    }
+
+    fn time_snak(property: &str, time: &str, precision: u64) -> Value {
+        let value = json!({
+            "time": time,
+            "timezone": 0,
+            "before": 0,
+            "after": 0,
+            "precision": precision,
+            "calendarmodel": "http://www.wikidata.org/entity/Q1985727"
+        });
+        snak(property, "time", value, "time")
+    }
+
+    fn title_snak(title: &str, language: &str) -> Value {
+        let value = json!({"text": title, "language": language});
+        snak("P1476", "monolingualtext", value, "monolingualtext")
+    }
+
+    /// The reference, read from an item with one statement that has `snaks` as its only reference
+    fn reference(snaks: Vec<Value>) -> Option<Reference> {
+        let mut snaks_by_property = serde_json::Map::new();
+        for snak in snaks {
+            let property = snak["property"].as_str().unwrap().to_string();
+            snaks_by_property
+                .entry(property)
+                .or_insert_with(|| json!([]))
+                .as_array_mut()
+                .unwrap()
+                .push(snak);
+        }
+        let j = json!({
+            "type": "item",
+            "id": "Q1",
+            "claims": {"P31": [{
+                "mainsnak": item_snak("P31", "Q5"),
+                "type": "statement",
+                "rank": "normal",
+                "references": [{"hash": "x", "snaks": snaks_by_property, "snaks-order": []}]
+            }]}
+        });
+        let entity = Entity::new_from_json(&j).unwrap();
+        let statement = entity.claims_with_property("P31")[0].to_owned();
+        Reference::new_from_snaks(statement.references()[0].snaks(), "en")
+    }
+
+    #[test]
+    fn test_reference_authors_and_dates() {
+        let r = reference(vec![
+            string_snak("P854", "url", "https://example.org/a"),
+            item_snak("P50", "Q42"),
+            string_snak("P2093", "string", "Jane Doe"),
+            time_snak("P577", "+2001-05-13T00:00:00Z", 11),
+            time_snak("P813", "+2013-12-00T00:00:00Z", 10),
+        ])
+        .unwrap();
+        assert_eq!(r.url, Some("https://example.org/a".to_string()));
+        assert_eq!(r.author_items, vec!["Q42".to_string()]);
+        assert_eq!(r.author_names, vec!["Jane Doe".to_string()]);
+        assert_eq!(r.publication_date, Some("2001-05-13".to_string()));
+        assert_eq!(r.date, Some("2013-12".to_string()));
+        assert!(r.entities_to_load().contains(&"Q42".to_string()));
+    }
+
+    #[test]
+    fn test_reference_archive_and_pages() {
+        let r = reference(vec![
+            string_snak("P854", "url", "https://example.org/b"),
+            string_snak("P1065", "url", "https://web.archive.org/web/2015/https://example.org/b"),
+            time_snak("P2960", "+2015-00-00T00:00:00Z", 9),
+            string_snak("P304", "string", "12-15"),
+        ])
+        .unwrap();
+        assert_eq!(
+            r.archive_url,
+            Some("https://web.archive.org/web/2015/https://example.org/b".to_string())
+        );
+        assert_eq!(r.archive_date, Some("2015".to_string()));
+        assert_eq!(r.pages, Some("12-15".to_string()));
+        assert!(r.has_url());
+    }
+
+    #[test]
+    fn test_reference_title_language() {
+        let r = reference(vec![
+            string_snak("P854", "url", "https://example.org/c"),
+            title_snak("Titel", "de"),
+        ])
+        .unwrap();
+        assert_eq!(r.title, Some("Titel".to_string()));
+        let r = reference(vec![
+            string_snak("P854", "url", "https://example.org/c"),
+            title_snak("Titel", "de"),
+            title_snak("Title", "en"),
+        ])
+        .unwrap();
+        assert_eq!(r.title, Some("Title".to_string()));
+    }
+
+    #[test]
+    fn test_reference_external_id() {
+        let r = reference(vec![
+            item_snak("P248", "Q36578"),
+            string_snak("P227", "external-id", "119033364"),
+            string_snak("P1810", "string", "Adams, Douglas"), // Not an external ID
+        ])
+        .unwrap();
+        assert_eq!(r.stated_in, Some("Q36578".to_string()));
+        assert_eq!(
+            r.external_id,
+            Some(("P227".to_string(), "119033364".to_string()))
+        );
+        assert!(!r.has_url());
+        assert!(r.entities_to_load().contains(&"P227".to_string()));
+
+        // An external ID on its own is a reference
+        assert!(reference(vec![string_snak("P214", "external-id", "113230702")]).is_some());
+        // A retrieval date on its own is not
+        assert!(reference(vec![time_snak("P813", "+2013-12-07T00:00:00Z", 11)]).is_none());
+    }
+
+    #[test]
+    fn test_reference_is_imported() {
+        let wikipedia_url = "https://en.wikipedia.org/w/index.php?title=Douglas_Adams&oldid=1";
+        let r = reference(vec![
+            item_snak("P143", "Q328"),
+            string_snak("P854", "url", wikipedia_url),
+        ])
+        .unwrap();
+        assert!(r.is_imported());
+        let r = reference(vec![
+            item_snak("P143", "Q328"),
+            string_snak("P4656", "url", wikipedia_url),
+            string_snak("P854", "url", wikipedia_url),
+        ])
+        .unwrap();
+        assert!(r.is_imported());
+
+        // An actual source besides the import
+        let r = reference(vec![
+            item_snak("P143", "Q328"),
+            string_snak("P854", "url", "https://example.org/a"),
+        ])
+        .unwrap();
+        assert!(!r.is_imported());
+        let r = reference(vec![item_snak("P143", "Q328"), item_snak("P248", "Q36578")]).unwrap();
+        assert!(!r.is_imported());
+        let r = reference(vec![
+            string_snak("P4656", "url", wikipedia_url),
+            string_snak("P227", "external-id", "119033364"),
+        ])
+        .unwrap();
+        assert!(!r.is_imported());
+
+        // Imported from another Wikimedia project
+        let r = reference(vec![
+            item_snak("P143", "Q15156406"),
+            string_snak("P854", "url", "https://en.wikisource.org/wiki/Page"),
+        ])
+        .unwrap();
+        assert!(!r.is_imported());
+
+        // A Wikipedia URL without an import
+        let r = reference(vec![string_snak("P854", "url", wikipedia_url)]).unwrap();
+        assert!(!r.is_imported());
+    }
+
+    #[test]
+    fn test_references_parameter_new() {
+        let p = |s: &str| ReferencesParameter::new(Some(&s.to_string()));
+        assert_eq!(ReferencesParameter::new(None), ReferencesParameter::None);
+        assert_eq!(p("all"), ReferencesParameter::All);
+        assert_eq!(p(" ALL "), ReferencesParameter::All);
+        assert_eq!(p("first"), ReferencesParameter::First);
+        assert_eq!(p("nowikipedia"), ReferencesParameter::NoWikipedia);
+        assert_eq!(p("no_wikipedia"), ReferencesParameter::NoWikipedia);
+        assert_eq!(p("withurl"), ReferencesParameter::WithUrl);
+        assert_eq!(p("with_url"), ReferencesParameter::WithUrl);
+        assert_eq!(
+            p("P569, p570"),
+            ReferencesParameter::Columns(vec!["P569".to_string(), "P570".to_string()])
+        );
+        assert_eq!(p("P569,label"), ReferencesParameter::None);
+        assert_eq!(p("garbage"), ReferencesParameter::None);
+        assert_eq!(p(""), ReferencesParameter::None);
+    }
+
+    #[test]
+    fn test_references_parameter_filter() {
+        let imported = reference(vec![
+            item_snak("P143", "Q328"),
+            string_snak("P4656", "url", "https://en.wikipedia.org/w/index.php?title=X&oldid=1"),
+            string_snak("P854", "url", "https://en.wikipedia.org/wiki/X"),
+        ])
+        .unwrap();
+        let stated_in = reference(vec![item_snak("P248", "Q36578")]).unwrap();
+        let with_url = reference(vec![string_snak("P854", "url", "https://example.org/a")]).unwrap();
+        let references = vec![imported.clone(), stated_in.clone(), with_url.clone()];
+
+        let filter = |mode: ReferencesParameter, suppress_imported: bool| {
+            mode.filter(references.clone(), suppress_imported)
+        };
+        assert_eq!(filter(ReferencesParameter::None, false), vec![]);
+        assert_eq!(filter(ReferencesParameter::All, false), references);
+        assert_eq!(
+            filter(ReferencesParameter::All, true),
+            vec![stated_in.clone(), with_url.clone()]
+        );
+        assert_eq!(
+            filter(ReferencesParameter::Columns(vec!["P569".to_string()]), false),
+            references
+        );
+        assert_eq!(filter(ReferencesParameter::First, false), vec![imported.clone()]);
+        assert_eq!(filter(ReferencesParameter::First, true), vec![stated_in.clone()]);
+        assert_eq!(
+            filter(ReferencesParameter::NoWikipedia, false),
+            vec![stated_in.clone(), with_url.clone()]
+        );
+        assert_eq!(
+            filter(ReferencesParameter::WithUrl, false),
+            vec![imported, with_url]
+        );
+    }
+}
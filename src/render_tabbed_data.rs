@@ -1,6 +1,88 @@
 pub use crate::column::*;
 use crate::*;
 use regex::RegexBuilder;
+use wikibase::snak::SnakDataType;
+
+/// Field types of tabular data pages on Commons
+#[derive(Debug, Clone, PartialEq)]
+enum TabbedDataType {
+    Number,
+    String,
+    Boolean,
+    Localized(String), // Language
+}
+
+impl TabbedDataType {
+    fn as_str(&self) -> &str {
+        match self {
+            Self::Number => "number",
+            Self::String => "string",
+            Self::Boolean => "boolean",
+            Self::Localized(_) => "localized",
+        }
+    }
+
+    /// Labels and descriptions are localized; property columns are numbers if the property is a quantity.
+    /// The type comes from the property datatype, never from the values, so IDs like "0123" stay strings.
+    /// SPARQL variable columns are booleans if the query returns only boolean literals for them.
+    fn new_for_column(list: &ListeriaList, column: &Column) -> Self {
+        let property = match &column.obj {
+            ColumnType::Field(varname) => return Self::new_for_variable(list, varname),
+            ColumnType::Number => return Self::Number,
+            ColumnType::Label | ColumnType::Description => {
+                return Self::Localized(list.language().to_owned())
+            }
+            ColumnType::LabelLang(language) | ColumnType::AliasLang(language) => {
+                return Self::Localized(language.to_owned())
+            }
+            ColumnType::Property(property) => property,
+            ColumnType::PropertyQualifier((_, qualifier)) => qualifier,
+            ColumnType::PropertyQualifierValue((_, _, qualifier)) => qualifier,
+            _ => return Self::String,
+        };
+        match list.ecw.get_datatype_for_property(property) {
+            SnakDataType::Quantity => Self::Number,
+            _ => Self::String,
+        }
+    }
+
+    fn new_for_variable(list: &ListeriaList, varname: &str) -> Self {
+        let values: Vec<&SparqlValue> = list
+            .sparql_rows()
+            .iter()
+            .filter_map(|row| {
+                row.iter()
+                    .find(|(k, _)| k.to_lowercase() == varname.to_lowercase())
+                    .map(|(_, v)| v)
+            })
+            .collect();
+        match !values.is_empty() && values.iter().all(|v| matches!(v, SparqlValue::Boolean(_))) {
+            true => Self::Boolean,
+            false => Self::String,
+        }
+    }
+
+    /// Converts the string value of a cell
+    fn convert(&self, value: &Value) -> Value {
+        let s = value.as_str().unwrap_or_default().trim();
+        if s.is_empty() {
+            return Value::Null;
+        }
+        match self {
+            Self::Number => match s.parse::<i64>() {
+                Ok(i) => json!(i),
+                Err(_) => s.parse::<f64>().map(|f| json!(f)).unwrap_or(Value::Null),
+            },
+            Self::String => json!(s),
+            Self::Boolean => match s {
+                "true" => json!(true),
+                "false" => json!(false),
+                _ => Value::Null,
+            },
+            Self::Localized(language) => json!({ language.to_owned(): s }),
+        }
+    }
+}
 
 pub struct RendererTabbedData {}
 
@@ -10,19 +92,7 @@ impl Renderer for RendererTabbedData {
     }
 
     fn render(&mut self, list: &ListeriaList) -> Result<String, String> {
-        let mut ret = json!({"license": "CC0-1.0","description": {"en":"Listeria output"},"sources":"https://github.com/magnusmanske/listeria_rs","schema":{"fields":[{ "name": "section", "type": "number", "title": { list.language().to_owned(): "Section"}}]},"data":[]});
-        list.columns().iter().enumerate().for_each(|(colnum,col)| {
-            if let Some(x) = ret["schema"]["fields"].as_array_mut() {
-                x.push(json!({"name":"col_".to_string()+&colnum.to_string(),"type":"string","title":{list.language().to_owned():col.label}}));
-            }
-        });
-        ret["data"] = list
-            .results()
-            .iter()
-            .enumerate()
-            .map(|(rownum, row)| row.as_tabbed_data(&list, rownum))
-            .collect();
-        Ok(format!("{}", ret))
+        Ok(format!("{}", self.as_tabbed_data(list)))
     }
 
     fn get_new_wikitext(
@@ -128,8 +198,50 @@ impl Renderer for RendererTabbedData {
 }
 
 impl RendererTabbedData {
-    pub fn tabbed_data_page_name(&self, list: &ListeriaList) -> Option<String> {
-        let ret = "Data:Listeria/".to_string() + &list.wiki() + "/" + &list.page_title() + ".tab";
+    pub fn as_tabbed_data(&self, list: &ListeriaList) -> Value {
+        let description = format!("Listeria output for {} on {}", list.page_title(), list.wiki());
+        let mut ret = json!({"license": "CC0-1.0","description": {"en":description},"sources":"https://github.com/magnusmanske/listeria_rs","schema":{"fields":[{ "name": "section", "type": "number", "title": { list.language().to_owned(): "Section"}}]},"data":[]});
+        let field_types: Vec<TabbedDataType> = list
+            .columns()
+            .iter()
+            .map(|col| TabbedDataType::new_for_column(list, col))
+            .collect();
+        list.columns().iter().enumerate().for_each(|(colnum,col)| {
+            if let Some(x) = ret["schema"]["fields"].as_array_mut() {
+                x.push(json!({"name":"col_".to_string()+&colnum.to_string(),"type":field_types[colnum].as_str(),"title":{list.language().to_owned():col.label}}));
+            }
+        });
+        ret["data"] = list
+            .results()
+            .iter()
+            .enumerate()
+            .map(|(rownum, row)| {
+                let values = row.as_tabbed_data(&list, rownum);
+                let values = values.as_array().cloned().unwrap_or_default();
+                // The first value is the section
+                let values: Vec<Value> = values
+                    .iter()
+                    .enumerate()
+                    .map(|(num, value)| match num {
+                        0 => value.to_owned(),
+                        num => match field_types.get(num - 1) {
+                            Some(field_type) => field_type.convert(value),
+                            None => value.to_owned(),
+                        },
+                    })
+                    .collect();
+                json!(values)
+            })
+            .collect();
+        ret
+    }
+
+    /// `Data:Listeria/WIKI/PAGE.tab`, or `Data:Listeria/WIKI/PAGE/2.tab` for the second list on the page
+    pub fn tabbed_data_page_name(&self, list: &ListeriaList, list_number: usize) -> Option<String> {
+        let ret = match list_number {
+            0 | 1 => format!("Data:Listeria/{}/{}.tab", list.wiki(), list.page_title()),
+            n => format!("Data:Listeria/{}/{}/{}.tab", list.wiki(), list.page_title(), n),
+        };
         if ret.len() > 250 {
             return None; // Page title too long
         }
@@ -141,10 +253,16 @@ impl RendererTabbedData {
         tabbed_data_json: Value,
         commons_api: &mut Api,
         list: &ListeriaList,
+        list_number: usize,
     ) -> Result<bool, String> {
         let data_page = self
-            .tabbed_data_page_name(list)
+            .tabbed_data_page_name(list, list_number)
             .ok_or("Data page name too long")?;
+        let summary = format!(
+            "Wikidata list updated [V2] for {} on {}",
+            list.page_title(),
+            list.wiki()
+        );
         let text = ::serde_json::to_string(&tabbed_data_json).map_err(|e| e.to_string())?;
         let token = commons_api
             .get_edit_token()
@@ -153,7 +271,8 @@ impl RendererTabbedData {
         let params: HashMap<String, String> = vec![
             ("action", "edit"),
             ("title", data_page.as_str()),
-            ("summary", "Listeria test"),
+            ("summary", summary.as_str()),
+            ("bot", "1"),
             ("text", text.as_str()),
             ("minor", "true"),
             ("recreate", "true"),
@@ -163,12 +282,20 @@ impl RendererTabbedData {
         .map(|x| (x.0.to_string(), x.1.to_string()))
         .collect();
         // No need to check if this is the same as the existing data; MW API will return OK but not actually edit
-        let _result = match commons_api.post_query_api_json_mut(&params).await {
+        let result = match commons_api.post_query_api_json_mut(&params).await {
             Ok(r) => r,
             Err(e) => return Err(format!("{:?}", e)),
         };
-        // TODO check ["edit"]["result"] == "Success"
-        Ok(true) //list.data_has_changed = true; // Just to make sure to update including page
+        match result["edit"]["result"].as_str() {
+            Some("Success") => Ok(result["edit"]["nochange"].is_null()),
+            _ => {
+                let info = result["error"]["info"]
+                    .as_str()
+                    .map(|s| s.to_string())
+                    .unwrap_or_else(|| result.to_string());
+                Err(format!("Writing {} failed: {}", data_page, info))
+            }
+        }
     }
 
     fn separate_start_template(&self, blob: &str) -> Option<(String, String)> {
@@ -198,3 +325,34 @@ impl RendererTabbedData {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_convert() {
+        assert_eq!(TabbedDataType::Number.convert(&json!("12")), json!(12));
+        assert_eq!(TabbedDataType::Number.convert(&json!("1.5")), json!(1.5));
+        assert_eq!(TabbedDataType::Number.convert(&json!("many")), Value::Null);
+        assert_eq!(TabbedDataType::String.convert(&json!("0123")), json!("0123"));
+        assert_eq!(TabbedDataType::Boolean.convert(&json!("true")), json!(true));
+        assert_eq!(TabbedDataType::Boolean.convert(&json!("false")), json!(false));
+        assert_eq!(TabbedDataType::Boolean.convert(&json!("yes")), Value::Null);
+        assert_eq!(TabbedDataType::Boolean.convert(&json!("")), Value::Null);
+        assert_eq!(
+            TabbedDataType::Localized("de".to_string()).convert(&json!("Berlin")),
+            json!({"de": "Berlin"})
+        );
+    }
+
+    #[test]
+    fn test_boolean_sparql_literal() {
+        let j = json!({"type": "literal", "datatype": "http://www.w3.org/2001/XMLSchema#boolean", "value": "true"});
+        assert_eq!(SparqlValue::new_from_json(&j), Some(SparqlValue::Boolean(true)));
+        let j = json!({"type": "literal", "datatype": "http://www.w3.org/2001/XMLSchema#boolean", "value": "0"});
+        assert_eq!(SparqlValue::new_from_json(&j), Some(SparqlValue::Boolean(false)));
+        let j = json!({"type": "literal", "value": "true"});
+        assert_eq!(SparqlValue::new_from_json(&j), Some(SparqlValue::Literal("true".to_string())));
+    }
+}
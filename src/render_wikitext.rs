@@ -1,28 +1,46 @@
 use crate::{ListeriaList, ListeriaPage, Renderer};
+use std::ops::Range;
 
-pub struct RendererWikitext {}
+pub struct RendererWikitext {
+    rows: Option<Range<usize>>, // Only render these result rows (for paginated lists)
+}
 
 impl Renderer for RendererWikitext {
     fn new() -> Self {
-        Self {}
+        Self { rows: None }
     }
 
     fn render(&mut self, list: &ListeriaList) -> Result<String, String> {
         let mut wt: String = list
             .get_section_ids()
             .iter()
+            .filter(|section_id| self.section_has_rows(list, **section_id))
             .map(|section_id| self.as_wikitext_section(list, *section_id))
             .collect();
 
+        // Notes below the list only go on the first page of a paginated list
+        if !self.is_first_page() {
+            return Ok(wt);
+        }
+
         if !list.shadow_files().is_empty() {
-            wt += "\n----\nThe following local image(s) are not shown in the above list, because they shadow a Commons image of the same name, and might be non-free:";
+            wt += "\n----\n";
+            wt += &list.message("shadow_files_notice");
             for file in list.shadow_files() {
                 wt += format!("\n# [[:{}:{}|]]", list.local_file_namespace_prefix(), file).as_str();
             }
         }
 
         if let Some("ITEMNUMBER") = list.summary().as_deref() {
-            wt += format!("\n----\n&sum; {} items.", list.results().len()).as_str();
+            let number = list.results().len().to_string();
+            wt += "\n----\n";
+            wt += &list.format_message("item_count", &[&number]);
+        }
+
+        if list.is_truncated() {
+            let number = list.results().len().to_string();
+            wt += "\n----\n";
+            wt += &list.format_message("list_truncated", &[&number]);
         }
 
         Ok(wt)
@@ -43,6 +61,34 @@ impl Renderer for RendererWikitext {
 }
 
 impl RendererWikitext {
+    /// Renders only the result rows in `rows`, for one page of a paginated list
+    pub fn new_for_rows(rows: Range<usize>) -> Self {
+        Self { rows: Some(rows) }
+    }
+
+    fn is_first_page(&self) -> bool {
+        match &self.rows {
+            Some(rows) => rows.start == 0,
+            None => true,
+        }
+    }
+
+    fn is_row_rendered(&self, row_index: usize) -> bool {
+        match &self.rows {
+            Some(rows) => rows.contains(&row_index),
+            None => true,
+        }
+    }
+
+    fn section_has_rows(&self, list: &ListeriaList, section_id: usize) -> bool {
+        self.rows.is_none()
+            || list
+                .results()
+                .iter()
+                .enumerate()
+                .any(|(row_index, row)| row.section() == section_id && self.is_row_rendered(row_index))
+    }
+
     fn as_wikitext_section(&self, list: &ListeriaList, section_id: usize) -> String {
         let mut wt = String::new();
 
@@ -61,21 +107,27 @@ impl RendererWikitext {
             wt += "|-\n";
         }
 
-        let row_entity_ids: Vec<String> = list
+        // Row numbers count within the section, also across pages
+        let section_rows: Vec<_> = list
             .results()
             .iter()
-            .filter(|row| row.section() == section_id)
-            .map(|row| row.entity_id())
+            .enumerate()
+            .filter(|(_row_index, row)| row.section() == section_id)
+            .enumerate()
+            .filter(|(_rownum, (row_index, _row))| self.is_row_rendered(*row_index))
+            .map(|(rownum, (_row_index, row))| (rownum, row))
+            .collect();
+
+        let row_entity_ids: Vec<String> = section_rows
+            .iter()
+            .map(|(_rownum, row)| row.entity_id())
             .cloned()
             .collect();
 
         // Rows
-        let rows = list
-            .results()
+        let rows = section_rows
             .iter()
-            .filter(|row| row.section() == section_id)
-            .enumerate()
-            .map(|(rownum, row)| row.as_wikitext(list, rownum))
+            .map(|(rownum, row)| row.as_wikitext(list, *rownum))
             .collect::<Vec<String>>();
         if list.skip_table() {
             wt += &rows.join("\n");
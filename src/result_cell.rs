@@ -94,14 +94,10 @@ impl ResultCell {
                     list.get_filtered_claims(&e, property)
                         .iter()
                         .for_each(|statement| {
-                            let references = match list.get_reference_parameter() {
-                                ReferencesParameter::All => {
-                                    Self::get_references_for_statement(&statement, list.language())
-                                }
-                                _ => None,
-                            };
+                            let references =
+                                Self::get_references_for_statement(&statement, list, property);
                             ret.parts.push(PartWithReference::new(
-                                ResultCellPart::from_snak(statement.main_snak()),
+                                ResultCellPart::from_snak(statement.main_snak(), list),
                                 references,
                             ));
                         });
@@ -112,7 +108,7 @@ impl ResultCell {
                     list.get_filtered_claims(&e, p1)
                         .iter()
                         .for_each(|statement| {
-                            ret.get_parts_p_p(statement, p2).iter().for_each(|part| {
+                            ret.get_parts_p_p(statement, p2, list).iter().for_each(|part| {
                                 ret.parts
                                     .push(PartWithReference::new(part.to_owned(), None))
                             });
@@ -124,7 +120,7 @@ impl ResultCell {
                     list.get_filtered_claims(&e, p1)
                         .iter()
                         .for_each(|statement| {
-                            ret.get_parts_p_q_p(statement, q1, p2)
+                            ret.get_parts_p_q_p(statement, q1, p2, list)
                                 .iter()
                                 .for_each(|part| {
                                     ret.parts
@@ -218,6 +214,7 @@ impl ResultCell {
         &self,
         statement: &wikibase::statement::Statement,
         property: &str,
+        list: &ListeriaList,
     ) -> Vec<ResultCellPart> {
         statement
             .qualifiers()
@@ -225,8 +222,8 @@ impl ResultCell {
             .filter(|snak| *snak.property() == *property)
             .map(|snak| {
                 ResultCellPart::SnakList(vec![
-                    PartWithReference::new(ResultCellPart::from_snak(statement.main_snak()), None),
-                    PartWithReference::new(ResultCellPart::from_snak(snak), None),
+                    PartWithReference::new(ResultCellPart::from_snak(statement.main_snak(), list), None),
+                    PartWithReference::new(ResultCellPart::from_snak(snak, list), None),
                 ])
             })
             .collect()
@@ -234,15 +231,25 @@ impl ResultCell {
 
     fn get_references_for_statement(
         statement: &wikibase::statement::Statement,
-        language: &str,
+        list: &ListeriaList,
+        property: &str,
     ) -> Option<Vec<Reference>> {
-        let references = statement.references();
-        let mut ret: Vec<Reference> = vec![];
-        for reference in references.iter() {
-            if let Some(r) = Reference::new_from_snaks(reference.snaks(), language) {
-                ret.push(r);
+        let mode = list.get_reference_parameter();
+        match mode {
+            ReferencesParameter::None => return None,
+            ReferencesParameter::Columns(properties) => {
+                if !properties.contains(&property.to_uppercase()) {
+                    return None;
+                }
             }
+            _ => {}
         }
+        let references: Vec<Reference> = statement
+            .references()
+            .iter()
+            .filter_map(|reference| Reference::new_from_snaks(reference.snaks(), list.language()))
+            .collect();
+        let ret = mode.filter(references, list.suppress_imported_references());
         if ret.is_empty() {
             None
         } else {
@@ -255,6 +262,7 @@ impl ResultCell {
         statement: &wikibase::statement::Statement,
         target_item: &str,
         property: &str,
+        list: &ListeriaList,
     ) -> Vec<ResultCellPart> {
         let links_to_target = match statement.main_snak().data_value() {
             Some(dv) => match dv.value() {
@@ -276,11 +284,11 @@ impl ResultCell {
                     /*
                     // prefix with the Q in p_q_p
                     PartWithReference::new(
-                        ResultCellPart::from_snak(statement.main_snak()),
+                        ResultCellPart::from_snak(statement.main_snak(), list),
                         None
                     ),
                     */
-                    PartWithReference::new(ResultCellPart::from_snak(snak), None),
+                    PartWithReference::new(ResultCellPart::from_snak(snak, list), None),
                 ])
             })
             .collect()
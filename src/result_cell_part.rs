@@ -62,6 +62,7 @@ impl ResultCellPart {
             SparqlValue::Time(x) => ResultCellPart::Text(x.to_owned()),
             SparqlValue::Location(x) => ResultCellPart::Location((x.lat, x.lon, None)),
             SparqlValue::Literal(x) => ResultCellPart::Text(x.to_owned()),
+            SparqlValue::Boolean(x) => ResultCellPart::Text(x.to_string()),
         }
     }
 
@@ -81,7 +82,7 @@ impl ResultCellPart {
         }
     }
 
-    pub fn from_snak(snak: &wikibase::Snak) -> Self {
+    pub fn from_snak(snak: &wikibase::Snak, list: &ListeriaList) -> Self {
         match &snak.data_value() {
             Some(dv) => match dv.value() {
                 wikibase::Value::Entity(v) => ResultCellPart::Entity((v.id().to_string(), true)),
@@ -93,7 +94,7 @@ impl ResultCellPart {
                     _ => ResultCellPart::Text(v.to_string()),
                 },
                 wikibase::Value::Quantity(v) => ResultCellPart::Text(v.amount().to_string()),
-                wikibase::Value::Time(v) => ResultCellPart::Time(ResultCellPart::reduce_time(&v)),
+                wikibase::Value::Time(v) => ResultCellPart::Time(ResultCellPart::reduce_time(&v, list)),
                 wikibase::Value::Coordinate(v) => {
                     ResultCellPart::Location((*v.latitude(), *v.longitude(), None))
                 }
@@ -101,11 +102,11 @@ impl ResultCellPart {
                     ResultCellPart::Text(v.language().to_string() + ":" + v.text())
                 }
             },
-            _ => ResultCellPart::Text("No/unknown value".to_string()),
+            _ => ResultCellPart::Text(list.message("no_value")),
         }
     }
 
-    pub fn reduce_time(v: &wikibase::TimeValue) -> String {
+    pub fn reduce_time(v: &wikibase::TimeValue, list: &ListeriaList) -> String {
         lazy_static! {
             static ref RE_DATE: Regex =
                 Regex::new(r#"^\+{0,1}(-{0,1}\d+)-(\d{1,2})-(\d{1,2})T"#).expect("RE_DATE does not parse");
@@ -122,9 +123,9 @@ impl ResultCellPart {
             }
         };
         match v.precision() {
-            6 => format!("{}th millenium", year[0..year.len() - 2].to_string()),
-            7 => format!("{}th century", year[0..year.len() - 2].to_string()),
-            8 => format!("{}0s", year[0..year.len() - 2].to_string()),
+            6 => list.format_message("millennium", &[&year[0..year.len() - 2]]),
+            7 => list.format_message("century", &[&year[0..year.len() - 2]]),
+            8 => list.format_message("decade", &[&format!("{}0", &year[0..year.len() - 2])]),
             9 => year,
             10 => format!("{}-{}", year, month),
             11 => format!("{}-{}-{}", year, month, day),
@@ -135,8 +136,8 @@ impl ResultCellPart {
     fn tabbed_string_safe(&self, s: String) -> String {
         let ret = s.replace("\n", " ").replace("\t", " ");
         // 400 chars Max
-        if ret.len() >= 380 {
-            ret[0..380].to_string();
+        if ret.chars().count() >= 380 {
+            return ret.chars().take(380).collect();
         }
         ret
     }
@@ -240,7 +241,7 @@ impl ResultCellPart {
                         match &col.obj {
                             ColumnType::Property(p) => {
                                 // Commons category
-                                if p == "P373" {
+                                if *p == list.wikibase_settings().property("P373") {
                                     format!("[[:commons:Category:{}|{}]]", text, text)
                                 } else {
                                     text.to_owned()
@@ -16,6 +16,7 @@ pub struct ResultRow {
     section: usize,
     sortkey: String,
     keep: bool,
+    raw_values: Vec<(String, String)>, // Extra row template parameters
 }
 
 impl ResultRow {
@@ -100,6 +101,80 @@ impl ResultRow {
             let x = ResultCell::new(list, &self.entity_id, sparql_rows, column).await;
             self.cells.push(x);
         }
+        if list.get_row_template().is_some() {
+            self.set_raw_values(list, sparql_rows);
+        }
+    }
+
+    /// Raw values need to be taken before entities are changed to local links
+    fn set_raw_values(&mut self, list: &ListeriaList, sparql_rows: &[&HashMap<String, SparqlValue>]) {
+        self.raw_values.clear();
+        if list.row_template_raw() {
+            for (colnum, cell) in self.cells.iter().enumerate() {
+                let key = match list.column(colnum) {
+                    Some(column) => column.obj.as_key(),
+                    None => continue,
+                };
+                let mut raw = vec![];
+                let mut qids = vec![];
+                let mut location = None;
+                for part in cell.parts() {
+                    match &part.part {
+                        ResultCellPart::Entity((id, _)) => {
+                            raw.push(id.to_owned());
+                            qids.push(id.to_owned());
+                        }
+                        ResultCellPart::LocalLink((page, _, _)) => raw.push(page.to_owned()),
+                        ResultCellPart::Time(t) => raw.push(t.to_owned()),
+                        ResultCellPart::Location((lat, lon, _)) => {
+                            raw.push(format!("{},{}", lat, lon));
+                            location = location.or(Some((*lat, *lon)));
+                        }
+                        ResultCellPart::File(f) => raw.push(f.to_owned()),
+                        ResultCellPart::Uri(u) => raw.push(u.to_owned()),
+                        ResultCellPart::ExternalId((_, id)) => raw.push(id.to_owned()),
+                        ResultCellPart::Text(t) => raw.push(Self::raw_number(t)),
+                        ResultCellPart::Number | ResultCellPart::SnakList(_) => {}
+                    }
+                }
+                if !raw.is_empty() {
+                    self.raw_values.push((format!("{}_raw", key), raw.join("; ")));
+                }
+                if !qids.is_empty() {
+                    self.raw_values.push((format!("{}_qid", key), qids.join("; ")));
+                }
+                if let Some((lat, lon)) = location {
+                    self.raw_values.push((format!("{}_lat", key), lat.to_string()));
+                    self.raw_values.push((format!("{}_lon", key), lon.to_string()));
+                }
+            }
+        }
+        for var in list.row_template_vars() {
+            let values: Vec<String> = sparql_rows
+                .iter()
+                .filter_map(|row| row.get(var))
+                .map(|v| match v {
+                    SparqlValue::Entity(x) => x.to_owned(),
+                    SparqlValue::File(x) => x.to_owned(),
+                    SparqlValue::Uri(x) => x.to_owned(),
+                    SparqlValue::Time(x) => x.to_owned(),
+                    SparqlValue::Location(x) => format!("{},{}", x.lat, x.lon),
+                    SparqlValue::Literal(x) => x.to_owned(),
+                    SparqlValue::Boolean(x) => x.to_string(),
+                })
+                .collect();
+            if !values.is_empty() {
+                self.raw_values.push((var.to_owned(), values.join("; ")));
+            }
+        }
+    }
+
+    /// Quantities come as eg "+1234"
+    fn raw_number(text: &str) -> String {
+        match text.strip_prefix('+') {
+            Some(number) if number.parse::<f64>().is_ok() => number.to_string(),
+            _ => text.to_string(),
+        }
     }
 
     pub fn set_sortkey(&mut self, sortkey: String) {
@@ -204,6 +279,7 @@ impl ResultRow {
         }
     }
 
+    /// Deterministic tie-break, so equal sortkeys do not flip-flop between runs
     fn compare_entiry_ids(&self, other: &ResultRow) -> Ordering {
         let id1 = self.entity_id[1..]
             .parse::<usize>()
@@ -215,19 +291,22 @@ impl ResultRow {
             .ok()
             .or(Some(0))
             .unwrap_or(0);
-        id1.partial_cmp(&id2).unwrap_or(Ordering::Equal)
+        id1.cmp(&id2)
+            .then_with(|| self.entity_id.cmp(&other.entity_id))
+    }
+
+    fn sortkey_as_number(&self) -> Option<f64> {
+        self.sortkey.trim_start_matches('+').parse::<f64>().ok()
     }
 
     pub fn compare_to(&self, other: &ResultRow, datatype: &SnakDataType) -> Ordering {
         match datatype {
             SnakDataType::Quantity => {
-                let va = self.sortkey.parse::<u64>().ok().or(Some(0)).unwrap_or(0);
-                let vb = other.sortkey.parse::<u64>().ok().or(Some(0)).unwrap_or(0);
-                if va == 0 && vb == 0 {
-                    self.compare_entiry_ids(other)
-                } else {
-                    va.partial_cmp(&vb).unwrap_or(Ordering::Equal)
-                }
+                let va = self.sortkey_as_number().unwrap_or(0.0);
+                let vb = other.sortkey_as_number().unwrap_or(0.0);
+                va.partial_cmp(&vb)
+                    .unwrap_or(Ordering::Equal)
+                    .then_with(|| self.compare_entiry_ids(other))
             }
             _ => {
                 if self.sortkey == other.sortkey {
@@ -252,8 +331,8 @@ impl ResultRow {
         json!(ret)
     }
 
-    fn cells_as_wikitext(&self, list: &ListeriaList, cells: &[String]) -> String {
-        cells
+    fn cells_as_wikitext(&self, list: &ListeriaList, cells: &[String], rownum: usize) -> String {
+        let mut ret: Vec<String> = cells
             .iter()
             .enumerate()
             .filter_map(|(colnum, cell)| match list.column(colnum) {
@@ -267,8 +346,17 @@ impl ResultRow {
                 }
                 _ => None,
             })
-            .collect::<Vec<String>>()
-            .join("\n| ")
+            .collect();
+        for (key, value) in &self.raw_values {
+            ret.push(format!("{} = {}", key, value.replace('|', "{{!}}")));
+        }
+        if list.row_template_raw() {
+            ret.push(format!("_row = {}", rownum + 1));
+            if let Some(section_name) = list.section_name(self.section) {
+                ret.push(format!("_section = {}", section_name.replace('|', "{{!}}")));
+            }
+        }
+        ret.join("\n| ")
     }
 
     pub fn as_wikitext(&self, list: &ListeriaList, rownum: usize) -> String {
@@ -282,7 +370,7 @@ impl ResultRow {
             Some(t) => format!(
                 "{{{{{}\n| {}\n}}}}",
                 t,
-                self.cells_as_wikitext(list, &cells)
+                self.cells_as_wikitext(list, &cells, rownum)
             ),
             None => "|".to_string() + &cells.join("\n|"),
         }
@@ -0,0 +1,374 @@
+use serde_json::Value;
+use std::fmt;
+use std::time::Duration;
+use tokio::time::sleep;
+
+const DEFAULT_ENDPOINT: &str = "https://query.wikidata.org/sparql";
+const DEFAULT_TIMEOUT_SECONDS: u64 = 90;
+const DEFAULT_RETRIES: u32 = 2;
+const DEFAULT_RETRY_DELAY_SECONDS: u64 = 5;
+const DEFAULT_MAX_RETRY_AFTER_SECONDS: u64 = 60;
+const DEFAULT_USER_AGENT: &str = concat!(
+    "Listeria/",
+    env!("CARGO_PKG_VERSION"),
+    " (https://github.com/magnusmanske/listeria_rs)"
+);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SparqlErrorKind {
+    QueryTimeout,   // The query service gave up on the query
+    NoResponse,     // No response within the configured timeout
+    RateLimited,    // HTTP 429
+    ServerError,    // HTTP 5xx
+    Network,        // Connection problems
+    Other,
+}
+
+#[derive(Debug, Clone)]
+pub struct SparqlError {
+    kind: SparqlErrorKind,
+    endpoint: String,
+    details: String,
+    retry_after: Option<Duration>,
+}
+
+impl SparqlError {
+    fn new(kind: SparqlErrorKind, endpoint: &str, details: String) -> Self {
+        Self {
+            kind,
+            endpoint: endpoint.to_string(),
+            details,
+            retry_after: None,
+        }
+    }
+
+    pub fn kind(&self) -> &SparqlErrorKind {
+        &self.kind
+    }
+
+    /// Failures that might go away if the query is run again a bit later
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self.kind,
+            SparqlErrorKind::RateLimited | SparqlErrorKind::ServerError | SparqlErrorKind::Network
+        )
+    }
+}
+
+/// The message is meant for the page editors, so it can go into the page or the status DB
+impl fmt::Display for SparqlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            SparqlErrorKind::QueryTimeout => write!(
+                f,
+                "The SPARQL query timed out on {}. Please simplify the query, or add a LIMIT.",
+                self.endpoint
+            ),
+            SparqlErrorKind::NoResponse => write!(
+                f,
+                "The SPARQL service {} did not respond in time ({}). Please try again later.",
+                self.endpoint, self.details
+            ),
+            SparqlErrorKind::RateLimited => write!(
+                f,
+                "The SPARQL service {} is rejecting queries because of too many requests. Please try again later.",
+                self.endpoint
+            ),
+            SparqlErrorKind::ServerError => write!(
+                f,
+                "The SPARQL service {} failed ({}). Please try again later.",
+                self.endpoint, self.details
+            ),
+            SparqlErrorKind::Network => write!(
+                f,
+                "The SPARQL service {} could not be reached ({}).",
+                self.endpoint, self.details
+            ),
+            SparqlErrorKind::Other => write!(
+                f,
+                "The SPARQL query on {} failed: {}",
+                self.endpoint, self.details
+            ),
+        }
+    }
+}
+
+/// A SPARQL service, as configured for an entry in `apis`, or as the default (top-level `sparql`)
+#[derive(Debug, Clone)]
+pub struct SparqlEndpoint {
+    url: String,
+    client: reqwest::Client,
+    cookie: Option<String>,
+    bearer_token: Option<String>,
+    timeout: Duration,
+    retries: u32,
+    retry_delay: Duration,
+    max_retry_after: Duration,
+}
+
+impl SparqlEndpoint {
+    /// Reads eg `{"endpoint":"https://commons-query.wikimedia.org/sparql","cookie":"wcqsOauth=...","user_agent":"...","timeout":120,"retries":2,"retry_delay":5,"max_retry_after":60,"oauth2":false}`
+    pub fn new_from_json(j: &Value, oauth2_token: &str) -> Result<Self, String> {
+        let url = j["endpoint"].as_str().unwrap_or(DEFAULT_ENDPOINT).to_string();
+        let timeout = Duration::from_secs(j["timeout"].as_u64().unwrap_or(DEFAULT_TIMEOUT_SECONDS));
+        let user_agent = j["user_agent"].as_str().unwrap_or(DEFAULT_USER_AGENT);
+        let client = reqwest::Client::builder()
+            .user_agent(user_agent)
+            .timeout(timeout)
+            .build()
+            .map_err(|e| e.to_string())?;
+        let bearer_token = match j["oauth2"].as_bool() {
+            Some(true) => Some(oauth2_token.to_string()),
+            _ => None,
+        };
+        Ok(Self {
+            url,
+            client,
+            cookie: j["cookie"].as_str().map(|s| s.to_string()),
+            bearer_token,
+            timeout,
+            retries: j["retries"].as_u64().map(|i| i as u32).unwrap_or(DEFAULT_RETRIES),
+            retry_delay: Duration::from_secs(
+                j["retry_delay"].as_u64().unwrap_or(DEFAULT_RETRY_DELAY_SECONDS),
+            ),
+            max_retry_after: Duration::from_secs(
+                j["max_retry_after"].as_u64().unwrap_or(DEFAULT_MAX_RETRY_AFTER_SECONDS),
+            ),
+        })
+    }
+
+    /// Same settings, different service
+    pub fn with_url(&self, url: &str) -> Self {
+        let mut ret = self.clone();
+        ret.url = url.to_string();
+        ret
+    }
+
+    /// Same settings, authenticated with an OAuth2 token
+    pub fn with_bearer_token(&self, token: &str) -> Self {
+        let mut ret = self.clone();
+        ret.bearer_token = Some(token.to_string());
+        ret
+    }
+
+    /// A cookie or OAuth2 token is configured
+    pub fn has_credentials(&self) -> bool {
+        self.cookie.is_some() || self.bearer_token.is_some()
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Runs the query, retrying transient failures with exponential backoff.
+    /// Fails right away if the server asks to wait longer than `max_retry_after`.
+    pub async fn query_with_retry(&self, sparql: &str) -> Result<Value, SparqlError> {
+        let mut attempt = 0;
+        loop {
+            let error = match self.query(sparql).await {
+                Ok(j) => return Ok(j),
+                Err(e) => e,
+            };
+            if attempt >= self.retries {
+                return Err(error);
+            }
+            let delay = match self.retry_delay_for(&error, attempt) {
+                Some(delay) => delay,
+                None => return Err(error),
+            };
+            tracing::warn!(
+                "SPARQL query failed ({:?}), retrying in {:?}: {}",
+                error.kind,
+                delay,
+                error.details
+            );
+            sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    /// How long to wait before the next attempt; None if the error is not worth retrying,
+    /// or the server asks to wait longer than `max_retry_after`
+    fn retry_delay_for(&self, error: &SparqlError, attempt: u32) -> Option<Duration> {
+        if !error.is_transient() {
+            return None;
+        }
+        match error.retry_after {
+            Some(retry_after) if retry_after > self.max_retry_after => None,
+            Some(retry_after) => Some(retry_after),
+            None => Some(
+                2u32.checked_pow(attempt)
+                    .and_then(|factor| self.retry_delay.checked_mul(factor))
+                    .unwrap_or(self.max_retry_after)
+                    .min(self.max_retry_after),
+            ),
+        }
+    }
+
+    pub async fn query(&self, sparql: &str) -> Result<Value, SparqlError> {
+        let mut request = self
+            .client
+            .post(&self.url)
+            .header(reqwest::header::ACCEPT, "application/sparql-results+json")
+            .form(&[("query", sparql), ("format", "json")]);
+        if let Some(cookie) = &self.cookie {
+            request = request.header(reqwest::header::COOKIE, cookie);
+        }
+        if let Some(token) = &self.bearer_token {
+            request = request.bearer_auth(token);
+        }
+        let response = request.send().await.map_err(|e| self.request_error(e))?;
+        let status = response.status();
+        let retry_after = response
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.trim().parse::<u64>().ok())
+            .map(Duration::from_secs);
+        let text = response.text().await.map_err(|e| self.request_error(e))?;
+        self.parse_response(status, retry_after, &text)
+    }
+
+    /// Result of a query, from the HTTP status, the Retry-After header, and the response body
+    fn parse_response(
+        &self,
+        status: reqwest::StatusCode,
+        retry_after: Option<Duration>,
+        text: &str,
+    ) -> Result<Value, SparqlError> {
+        // WDQS reports query timeouts as a Java exception, with HTTP 500
+        if !status.is_success() && text.contains("java.util.concurrent.TimeoutException") {
+            return Err(SparqlError::new(
+                SparqlErrorKind::QueryTimeout,
+                &self.url,
+                "TimeoutException".to_string(),
+            ));
+        }
+        if status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            let mut error = SparqlError::new(
+                SparqlErrorKind::RateLimited,
+                &self.url,
+                format!("HTTP status {}", status),
+            );
+            error.retry_after = retry_after;
+            return Err(error);
+        }
+        if status.is_server_error() {
+            return Err(SparqlError::new(
+                SparqlErrorKind::ServerError,
+                &self.url,
+                format!("HTTP status {}", status),
+            ));
+        }
+        if !status.is_success() {
+            let first_line = text.lines().next().unwrap_or_default();
+            return Err(SparqlError::new(
+                SparqlErrorKind::Other,
+                &self.url,
+                format!("HTTP status {}: {}", status, first_line),
+            ));
+        }
+        serde_json::from_str(text).map_err(|e| {
+            SparqlError::new(
+                SparqlErrorKind::Other,
+                &self.url,
+                format!("invalid JSON in response: {}", e),
+            )
+        })
+    }
+
+    fn request_error(&self, e: reqwest::Error) -> SparqlError {
+        match e.is_timeout() {
+            true => SparqlError::new(
+                SparqlErrorKind::NoResponse,
+                &self.url,
+                format!("{} seconds", self.timeout.as_secs()),
+            ),
+            false => SparqlError::new(SparqlErrorKind::Network, &self.url, e.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::StatusCode;
+
+    fn endpoint(j: Value) -> SparqlEndpoint {
+        SparqlEndpoint::new_from_json(&j, "").unwrap()
+    }
+
+    fn error(kind: SparqlErrorKind, retry_after: Option<u64>) -> SparqlError {
+        let mut error = SparqlError::new(kind, DEFAULT_ENDPOINT, String::new());
+        error.retry_after = retry_after.map(Duration::from_secs);
+        error
+    }
+
+    #[test]
+    fn test_parse_response_rate_limited() {
+        let endpoint = endpoint(json!({}));
+        let error = endpoint
+            .parse_response(StatusCode::TOO_MANY_REQUESTS, Some(Duration::from_secs(30)), "")
+            .unwrap_err();
+        assert_eq!(*error.kind(), SparqlErrorKind::RateLimited);
+        assert_eq!(error.retry_after, Some(Duration::from_secs(30)));
+        assert!(error.is_transient());
+    }
+
+    #[test]
+    fn test_parse_response_server_error() {
+        let endpoint = endpoint(json!({}));
+        let error = endpoint
+            .parse_response(StatusCode::BAD_GATEWAY, None, "Bad gateway")
+            .unwrap_err();
+        assert_eq!(*error.kind(), SparqlErrorKind::ServerError);
+        assert!(error.is_transient());
+        let error = endpoint
+            .parse_response(StatusCode::BAD_REQUEST, None, "Parse error\nat line 1")
+            .unwrap_err();
+        assert_eq!(*error.kind(), SparqlErrorKind::Other);
+        assert!(!error.is_transient());
+    }
+
+    #[test]
+    fn test_parse_response_timeout() {
+        let endpoint = endpoint(json!({}));
+        let body = "SPARQL-QUERY: ...\njava.util.concurrent.TimeoutException\n\tat ...";
+        let error = endpoint
+            .parse_response(StatusCode::INTERNAL_SERVER_ERROR, None, body)
+            .unwrap_err();
+        assert_eq!(*error.kind(), SparqlErrorKind::QueryTimeout);
+        assert!(!error.is_transient());
+
+        // A successful result that happens to contain the text
+        let body = r#"{"head":{"vars":["x"]},"results":{"bindings":[{"x":{"type":"literal","value":"java.util.concurrent.TimeoutException"}}]}}"#;
+        let j = endpoint.parse_response(StatusCode::OK, None, body).unwrap();
+        assert_eq!(
+            j["results"]["bindings"][0]["x"]["value"],
+            "java.util.concurrent.TimeoutException"
+        );
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        let endpoint = endpoint(json!({"retry_delay":5,"max_retry_after":60}));
+        let server_error = error(SparqlErrorKind::ServerError, None);
+        assert_eq!(endpoint.retry_delay_for(&server_error, 0), Some(Duration::from_secs(5)));
+        assert_eq!(endpoint.retry_delay_for(&server_error, 2), Some(Duration::from_secs(20)));
+        assert_eq!(endpoint.retry_delay_for(&server_error, 4), Some(Duration::from_secs(60)));
+        // Would overflow
+        assert_eq!(endpoint.retry_delay_for(&server_error, 40), Some(Duration::from_secs(60)));
+
+        let rate_limited = error(SparqlErrorKind::RateLimited, Some(30));
+        assert_eq!(endpoint.retry_delay_for(&rate_limited, 0), Some(Duration::from_secs(30)));
+        let rate_limited = error(SparqlErrorKind::RateLimited, Some(3600));
+        assert_eq!(endpoint.retry_delay_for(&rate_limited, 0), None);
+
+        let timeout = error(SparqlErrorKind::QueryTimeout, None);
+        assert_eq!(endpoint.retry_delay_for(&timeout, 0), None);
+    }
+}
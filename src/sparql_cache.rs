@@ -0,0 +1,201 @@
+use crate::disk_cache::DiskPruner;
+use chrono::Utc;
+use lru::LruCache;
+use serde_json::Value;
+use std::fs;
+use std::io::Write;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+use tempfile::NamedTempFile;
+
+/// The disk cache is pruned after this many writes
+const PRUNE_DISK_EVERY_WRITES: usize = 100;
+
+#[derive(Debug, Clone)]
+struct CachedResult {
+    timestamp: i64,
+    result: Value,
+    size: usize, // Bytes of the serialized result
+}
+
+/// In-memory results, bounded by number of entries and total size
+#[derive(Debug)]
+struct MemoryCache {
+    entries: LruCache<String, CachedResult>,
+    size: usize,
+    max_size: usize,
+}
+
+impl MemoryCache {
+    fn get(&mut self, key: &str) -> Option<&CachedResult> {
+        self.entries.get(&key.to_string())
+    }
+
+    fn put(&mut self, key: String, cached: CachedResult) {
+        if cached.size > self.max_size {
+            return;
+        }
+        self.size += cached.size;
+        // `push` returns the replaced entry, or the one evicted for exceeding max_entries
+        if let Some((_, old)) = self.entries.push(key, cached) {
+            self.size -= old.size;
+        }
+        while self.size > self.max_size {
+            match self.entries.pop_lru() {
+                Some((_, old)) => self.size -= old.size,
+                None => break,
+            }
+        }
+    }
+}
+
+/// Cache for SPARQL results, keyed on endpoint and (expanded) query text.
+/// Recent results are kept in memory; if a path is configured, they are also written to disk, and survive bot restarts.
+#[derive(Debug)]
+pub struct SparqlCache {
+    path: Option<PathBuf>,
+    ttl: i64,
+    max_result_size: usize,
+    memory: Mutex<MemoryCache>,
+    pruner: Option<DiskPruner>, // If there is a path
+}
+
+impl SparqlCache {
+    pub fn new(
+        path: Option<PathBuf>,
+        ttl: i64,
+        max_entries: usize,
+        max_result_size: usize,
+        max_memory_size: usize,
+        max_disk_size: Option<u64>,
+    ) -> Result<Self, String> {
+        if let Some(path) = &path {
+            fs::create_dir_all(path)
+                .map_err(|e| format!("Cannot create SPARQL cache directory {:?}: {}", path, e))?;
+        }
+        let max_entries =
+            NonZeroUsize::new(max_entries).ok_or("SPARQL cache max_entries must not be 0")?;
+        let pruner = path.as_ref().map(|path| {
+            let max_age = Duration::from_secs(ttl.max(0) as u64);
+            DiskPruner::new(path.clone(), max_age, max_disk_size, PRUNE_DISK_EVERY_WRITES)
+        });
+        if let Some(pruner) = &pruner {
+            pruner.prune(); // Leftovers from earlier runs
+        }
+        Ok(Self {
+            path,
+            ttl,
+            max_result_size,
+            memory: Mutex::new(MemoryCache {
+                entries: LruCache::new(max_entries),
+                size: 0,
+                max_size: max_memory_size,
+            }),
+            pruner,
+        })
+    }
+
+    /// Reads eg `{"path":"/data/sparql_cache","ttl":3600,"max_entries":500,"max_result_size":10000000,"max_memory_size":200000000,"max_disk_size":2000000000}`
+    pub fn new_from_json(j: &Value) -> Result<Self, String> {
+        let path = j["path"].as_str().map(PathBuf::from);
+        let ttl = j["ttl"].as_i64().unwrap_or(3600);
+        let max_entries = j["max_entries"].as_u64().unwrap_or(500) as usize;
+        let max_result_size = j["max_result_size"].as_u64().unwrap_or(10_000_000) as usize;
+        let max_memory_size = j["max_memory_size"].as_u64().unwrap_or(200_000_000) as usize;
+        let max_disk_size = j["max_disk_size"].as_u64();
+        Self::new(
+            path,
+            ttl,
+            max_entries,
+            max_result_size,
+            max_memory_size,
+            max_disk_size,
+        )
+    }
+
+    /// Maximum age, in seconds, of a cached result
+    pub fn ttl(&self) -> i64 {
+        self.ttl
+    }
+
+    fn key(endpoint: &str, sparql: &str) -> String {
+        format!("{:x}", md5::compute(format!("{}\n{}", endpoint, sparql)))
+    }
+
+    fn file_path(&self, key: &str) -> Option<PathBuf> {
+        let mut path = self.path.clone()?;
+        path.push(&key[0..2]);
+        path.push(format!("{}.json", key));
+        Some(path)
+    }
+
+    /// Returns the cached result, if it is no older than `max_age` seconds (and the cache TTL)
+    pub fn get(&self, endpoint: &str, sparql: &str, max_age: Option<i64>) -> Option<Value> {
+        let max_age = max_age.unwrap_or(self.ttl).min(self.ttl);
+        if max_age <= 0 {
+            return None;
+        }
+        let key = Self::key(endpoint, sparql);
+        let now = Utc::now().timestamp();
+        if let Some(cached) = self.memory.lock().ok()?.get(&key) {
+            if now - cached.timestamp <= max_age {
+                return Some(cached.result.to_owned());
+            }
+        }
+
+        let path = self.file_path(&key)?;
+        let text = fs::read_to_string(&path).ok()?;
+        let j: Value = serde_json::from_str(&text).ok()?;
+        let cached = CachedResult {
+            timestamp: j["timestamp"].as_i64()?,
+            result: j["result"].to_owned(),
+            size: text.len(),
+        };
+        let age = now - cached.timestamp;
+        if age > self.ttl {
+            let _ = fs::remove_file(&path); // Expired for everyone
+            return None;
+        }
+        let ret = match age <= max_age {
+            true => Some(cached.result.to_owned()),
+            false => None,
+        };
+        self.memory.lock().ok()?.put(key, cached);
+        ret
+    }
+
+    pub fn set(&self, endpoint: &str, sparql: &str, result: &Value) -> Result<(), String> {
+        let text = result.to_string();
+        if text.len() > self.max_result_size {
+            return Ok(()); // Too large to cache
+        }
+        let key = Self::key(endpoint, sparql);
+        let timestamp = Utc::now().timestamp();
+        self.memory.lock().map_err(|e| e.to_string())?.put(
+            key.to_owned(),
+            CachedResult {
+                timestamp,
+                result: result.to_owned(),
+                size: text.len(),
+            },
+        );
+
+        let path = match self.file_path(&key) {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let dir = path.parent().ok_or("No parent directory")?;
+        fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+        let j = json!({"timestamp": timestamp, "endpoint": endpoint, "result": result});
+        let mut file = NamedTempFile::new_in(dir).map_err(|e| e.to_string())?;
+        file.write_all(j.to_string().as_bytes())
+            .map_err(|e| e.to_string())?;
+        file.persist(&path).map_err(|e| e.to_string())?;
+        if let Some(pruner) = &self.pruner {
+            pruner.add_write();
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,331 @@
+use regex::Regex;
+use std::collections::HashMap;
+
+/// A list row, parsed back from rendered wikitext
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableRow {
+    pub key: String,
+    pub cells: Vec<String>,
+}
+
+impl TableRow {
+    /// `attributes` are those of the row (eg `class='wd_q42'`), used for finding the entity ID only
+    fn new(attributes: &str, cells: Vec<String>) -> Option<Self> {
+        lazy_static! {
+            static ref RE_ENTITY_KEY: Regex =
+                Regex::new(r#"(?:wd_|\[\[(?::?[a-z]+:)?)([QqPpLlMm]\d+)\b"#)
+                    .expect("RE_ENTITY_KEY does not parse");
+        }
+        let cells: Vec<String> = cells
+            .iter()
+            .map(|c| c.trim().to_string())
+            .filter(|c| !c.is_empty())
+            .collect();
+        if cells.is_empty() {
+            return None;
+        }
+        let key = std::iter::once(attributes)
+            .chain(cells.iter().map(|cell| cell.as_str()))
+            .find_map(|text| RE_ENTITY_KEY.captures(text))
+            .and_then(|caps| caps.get(1))
+            .map(|m| m.as_str().to_uppercase())
+            .unwrap_or_else(|| cells[0].to_owned());
+        Some(Self { key, cells })
+    }
+}
+
+/// Parses the rows of wikitext tables (`{| ... |}`), or row template invocations (`{{Row\n| x = y\n}}`)
+pub fn parse_rows(wikitext: &str) -> Vec<TableRow> {
+    parse_rows_and_other_lines(wikitext).0
+}
+
+/// Rows, and the lines that are not part of any row (table headers, captions, text around the tables)
+fn parse_rows_and_other_lines(wikitext: &str) -> (Vec<TableRow>, Vec<String>) {
+    let mut ret = vec![];
+    let mut other_lines: Vec<String> = vec![];
+    let mut cells: Vec<String> = vec![];
+    let mut attributes = String::new();
+    let mut in_table = false;
+    let mut in_row_template = false;
+    for line in wikitext.lines() {
+        let line = line.trim_end();
+        if in_row_template {
+            if line.starts_with("}}") {
+                in_row_template = false;
+                ret.extend(TableRow::new("", std::mem::take(&mut cells)));
+            } else if let Some(param) = line.strip_prefix('|') {
+                cells.push(param.to_string());
+            } else if let Some(last) = cells.last_mut() {
+                *last += "\n";
+                *last += line;
+            }
+            continue;
+        }
+        if line.starts_with("{|") {
+            in_table = true;
+            other_lines.push(line.to_string());
+            continue;
+        }
+        if !in_table {
+            if line.starts_with("{{") && !line.contains("}}") {
+                in_row_template = true;
+            } else {
+                other_lines.push(line.to_string());
+            }
+            continue;
+        }
+        if line.starts_with("|}") {
+            in_table = false;
+            ret.extend(TableRow::new(&attributes, std::mem::take(&mut cells)));
+            attributes.clear();
+            other_lines.push(line.to_string());
+        } else if let Some(row_attributes) = line.strip_prefix("|-") {
+            ret.extend(TableRow::new(&attributes, std::mem::take(&mut cells)));
+            attributes = row_attributes.to_string();
+        } else if line.starts_with('!') || line.starts_with("|+") {
+            // Header/caption
+            other_lines.push(line.to_string());
+        } else if let Some(row) = line.strip_prefix('|') {
+            cells.extend(row.split("||").map(|s| s.to_string()));
+        } else if let Some(last) = cells.last_mut() {
+            *last += "\n";
+            *last += line;
+        } else {
+            other_lines.push(line.to_string());
+        }
+    }
+    (ret, other_lines)
+}
+
+/// Differences between the rows of two renderings of a list
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TableDiff {
+    pub added: usize,
+    pub removed: usize,
+    pub changed_cells: usize,
+}
+
+impl TableDiff {
+    pub fn new(old_wikitext: &str, new_wikitext: &str) -> Self {
+        let mut old_rows: HashMap<String, Vec<TableRow>> = HashMap::new();
+        for row in parse_rows(old_wikitext) {
+            old_rows.entry(row.key.to_owned()).or_default().push(row);
+        }
+        let mut ret = Self::default();
+        for new_row in parse_rows(new_wikitext) {
+            let old_row = match old_rows.get_mut(&new_row.key) {
+                Some(rows) if !rows.is_empty() => rows.remove(0),
+                _ => {
+                    ret.added += 1;
+                    continue;
+                }
+            };
+            let num_cells = old_row.cells.len().max(new_row.cells.len());
+            ret.changed_cells += (0..num_cells)
+                .filter(|num| old_row.cells.get(*num) != new_row.cells.get(*num))
+                .count();
+        }
+        ret.removed = old_rows.values().map(|rows| rows.len()).sum();
+        ret
+    }
+
+    pub fn add(&mut self, other: &TableDiff) {
+        self.added += other.added;
+        self.removed += other.removed;
+        self.changed_cells += other.changed_cells;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added == 0 && self.removed == 0 && self.changed_cells == 0
+    }
+
+    /// eg "+3 items, −1 item, 12 cells changed"
+    pub fn as_summary(&self) -> String {
+        let mut parts = vec![];
+        if self.added > 0 {
+            parts.push(format!("+{} {}", self.added, Self::plural(self.added, "item", "items")));
+        }
+        if self.removed > 0 {
+            parts.push(format!("−{} {}", self.removed, Self::plural(self.removed, "item", "items")));
+        }
+        if self.changed_cells > 0 {
+            parts.push(format!(
+                "{} {} changed",
+                self.changed_cells,
+                Self::plural(self.changed_cells, "cell", "cells")
+            ));
+        }
+        parts.join(", ")
+    }
+
+    fn plural<'a>(number: usize, singular: &'a str, plural: &'a str) -> &'a str {
+        match number {
+            1 => singular,
+            _ => plural,
+        }
+    }
+}
+
+/// Removes content that can change between runs without the data changing:
+/// reference names, and whitespace
+pub(crate) fn normalize_volatile(wikitext: &str) -> String {
+    lazy_static! {
+        static ref RE_REF_NAME_SHORT: Regex =
+            Regex::new(r#"<ref name=("[^"]*"|'[^']*')\s*/>"#).expect("RE_REF_NAME_SHORT does not parse");
+        static ref RE_REF_NAME: Regex =
+            Regex::new(r#"<ref name=("[^"]*"|'[^']*')\s*>"#).expect("RE_REF_NAME does not parse");
+    }
+    let ret = RE_REF_NAME_SHORT.replace_all(wikitext, "<ref/>");
+    let ret = RE_REF_NAME.replace_all(&ret, "<ref>");
+    ret.lines()
+        .map(|line| line.trim_end())
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<&str>>()
+        .join("\n")
+}
+
+/// Compares two renderings of a list, ignoring volatile content.
+/// If `ignore_order` is set (unsorted lists), whole rows may be in any order within their section.
+pub fn is_semantically_equal(old_wikitext: &str, new_wikitext: &str, ignore_order: bool) -> bool {
+    let old_wikitext = normalize_volatile(old_wikitext);
+    let new_wikitext = normalize_volatile(new_wikitext);
+    if old_wikitext == new_wikitext {
+        return true;
+    }
+    if !ignore_order {
+        return false;
+    }
+    let old_sections: Vec<&str> = old_wikitext.split("\n==").collect();
+    let new_sections: Vec<&str> = new_wikitext.split("\n==").collect();
+    if old_sections.len() != new_sections.len() {
+        return false;
+    }
+    old_sections
+        .iter()
+        .zip(new_sections.iter())
+        .all(|(old_section, new_section)| {
+            let (mut old_rows, old_other_lines) = parse_rows_and_other_lines(old_section);
+            let (mut new_rows, new_other_lines) = parse_rows_and_other_lines(new_section);
+            if old_other_lines != new_other_lines {
+                return false;
+            }
+            // Rows are compared as a whole, so values that moved between rows are a change
+            old_rows.sort_by(|a, b| (&a.key, &a.cells).cmp(&(&b.key, &b.cells)));
+            new_rows.sort_by(|a, b| (&a.key, &a.cells).cmp(&(&b.key, &b.cells)));
+            old_rows == new_rows
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TABLE: &str = "{| class='wikitable sortable'\n! Name\n! Born\n|-\n| [[Q1|Alice]]\n| 1900\n|-\n| [[Q2|Bob]]\n| 1910\n|}";
+
+    #[test]
+    fn test_parse_rows_table() {
+        let rows = parse_rows(TABLE);
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].key, "Q1");
+        assert_eq!(rows[0].cells, vec!["[[Q1|Alice]]", "1900"]);
+        assert_eq!(rows[1].key, "Q2");
+    }
+
+    #[test]
+    fn test_parse_rows_attributes_and_inline_cells() {
+        let wikitext = "{| class='wikitable'\n|- class='wd_q42'\n| Douglas Adams || 1952\n|}";
+        let rows = parse_rows(wikitext);
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].key, "Q42");
+        assert_eq!(rows[0].cells, vec!["Douglas Adams", "1952"]);
+    }
+
+    #[test]
+    fn test_parse_rows_row_template() {
+        let wikitext = "{{Row\n| item = [[Q1|Alice]]\n| born = 1900\n}}\n{{Row\n| item = [[Q2|Bob]]\n}}";
+        let rows = parse_rows(wikitext);
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].key, "Q1");
+        assert_eq!(rows[0].cells, vec!["item = [[Q1|Alice]]", "born = 1900"]);
+        assert_eq!(rows[1].key, "Q2");
+    }
+
+    #[test]
+    fn test_table_diff_counts() {
+        let new = "{| class='wikitable sortable'\n! Name\n! Born\n|-\n| [[Q1|Alice]]\n| 1901\n|-\n| [[Q3|Carol]]\n| 1920\n|-\n| [[Q4|Dave]]\n| 1930\n|}";
+        let diff = TableDiff::new(TABLE, new);
+        assert_eq!(
+            diff,
+            TableDiff {
+                added: 2,
+                removed: 1,
+                changed_cells: 1
+            }
+        );
+        assert!(TableDiff::new(TABLE, TABLE).is_empty());
+    }
+
+    #[test]
+    fn test_table_diff_summary() {
+        let diff = TableDiff {
+            added: 3,
+            removed: 1,
+            changed_cells: 12,
+        };
+        assert_eq!(diff.as_summary(), "+3 items, −1 item, 12 cells changed");
+        let diff = TableDiff {
+            added: 1,
+            removed: 0,
+            changed_cells: 1,
+        };
+        assert_eq!(diff.as_summary(), "+1 item, 1 cell changed");
+        assert_eq!(TableDiff::default().as_summary(), "");
+    }
+
+    #[test]
+    fn test_semantically_equal_reordered_rows() {
+        let reordered = "{| class='wikitable sortable'\n! Name\n! Born\n|-\n| [[Q2|Bob]]\n| 1910\n|-\n| [[Q1|Alice]]\n| 1900\n|}";
+        assert!(is_semantically_equal(TABLE, reordered, true));
+        assert!(!is_semantically_equal(TABLE, reordered, false));
+    }
+
+    #[test]
+    fn test_semantically_equal_detects_swap_between_rows() {
+        let swapped = "{| class='wikitable sortable'\n! Name\n! Born\n|-\n| [[Q1|Alice]]\n| 1910\n|-\n| [[Q2|Bob]]\n| 1900\n|}";
+        assert!(!is_semantically_equal(TABLE, swapped, true));
+    }
+
+    #[test]
+    fn test_semantically_equal_detects_swap_between_row_templates() {
+        let old = "{{Row\n| item = Q1\n| born = 1900\n}}\n{{Row\n| item = Q2\n| born = 1910\n}}";
+        let new = "{{Row\n| item = Q1\n| born = 1910\n}}\n{{Row\n| item = Q2\n| born = 1900\n}}";
+        assert!(!is_semantically_equal(old, new, true));
+        let reordered = "{{Row\n| item = Q2\n| born = 1910\n}}\n{{Row\n| item = Q1\n| born = 1900\n}}";
+        assert!(is_semantically_equal(old, reordered, true));
+    }
+
+    #[test]
+    fn test_semantically_equal_ignores_volatile_content() {
+        // As written by `Reference::as_reference`
+        let row = |ref_name: &str| {
+            format!(
+                "{{| class='wikitable'\n|-\n| [[Q1|Alice]]<ref name='ref_{}'>x</ref>\n|-\n| [[Q2|Bob]]<ref name='ref_{}' />  \n\n|}}",
+                ref_name, ref_name
+            )
+        };
+        assert!(is_semantically_equal(&row("1a2b"), &row("3c4d"), false));
+    }
+
+    #[test]
+    fn test_semantically_equal_region_change() {
+        let old = "{| class='wikitable'\n|-\n| {{Coord|52|13|region=DE-BE}}\n|}";
+        let new = "{| class='wikitable'\n|-\n| {{Coord|52|13|region=DE-BB}}\n|}";
+        assert!(!is_semantically_equal(old, new, false));
+    }
+
+    #[test]
+    fn test_semantically_equal_header_change() {
+        let new = TABLE.replace("! Born", "! Birth");
+        assert!(!is_semantically_equal(TABLE, &new, true));
+    }
+}
@@ -0,0 +1,233 @@
+//! Tokenizer for the inside of template invocations, eg `|sparql=...|columns=...}}`.
+//! Understands `<!-- comments -->` (removed), `<nowiki>` (kept literally, without the tags),
+//! `[[links|with pipes]]`, nested templates, parser functions and `{{{parameters}}}`.
+//! As before, single braces (SPARQL) nest, and pipes inside quotes do not split parameters.
+
+/// Returns (end position, literal text) for a comment or nowiki section starting at `pos`
+fn special_at(text: &str, pos: usize) -> Option<Result<(usize, String), String>> {
+    let rest = &text[pos..];
+    if rest.starts_with("<!--") {
+        return Some(match rest[4..].find("-->") {
+            Some(end) => Ok((pos + 4 + end + 3, String::new())),
+            None => Err("Unclosed comment".to_string()),
+        });
+    }
+    if starts_with_ignore_case(rest, "<nowiki/>") {
+        return Some(Ok((pos + 9, String::new())));
+    }
+    if starts_with_ignore_case(rest, "<nowiki />") {
+        return Some(Ok((pos + 10, String::new())));
+    }
+    if starts_with_ignore_case(rest, "<nowiki>") {
+        let inner = &rest[8..];
+        return Some(match find_ignore_case(inner, "</nowiki>") {
+            Some(end) => Ok((pos + 8 + end + 9, inner[0..end].to_string())),
+            None => Err("Unclosed nowiki".to_string()),
+        });
+    }
+    None
+}
+
+fn starts_with_ignore_case(text: &str, prefix: &str) -> bool {
+    text.get(0..prefix.len())
+        .map(|s| s.eq_ignore_ascii_case(prefix))
+        .unwrap_or(false)
+}
+
+fn find_ignore_case(text: &str, needle: &str) -> Option<usize> {
+    (0..text.len())
+        .filter(|pos| text.is_char_boundary(*pos))
+        .find(|pos| starts_with_ignore_case(&text[*pos..], needle))
+}
+
+/// Splits the template parameters at top-level pipes; the first part is whatever comes before the first pipe
+pub fn split_template_params(text: &str) -> Result<Vec<String>, String> {
+    let mut parts: Vec<String> = vec![];
+    let mut part = String::new();
+    let mut curly_braces: i64 = 0;
+    let mut links: usize = 0;
+    let mut quote_char: Option<char> = None;
+    let mut pos = 0;
+    while pos < text.len() {
+        if let Some(special) = special_at(text, pos) {
+            let (end, literal) = special?;
+            part += &literal;
+            pos = end;
+            continue;
+        }
+        let rest = &text[pos..];
+        if rest.starts_with("[[") {
+            links += 1;
+            part += "[[";
+            pos += 2;
+            continue;
+        }
+        if rest.starts_with("]]") && links > 0 {
+            links -= 1;
+            part += "]]";
+            pos += 2;
+            continue;
+        }
+        let c = match rest.chars().next() {
+            Some(c) => c,
+            None => break,
+        };
+        match c {
+            '\'' | '"' if links == 0 => {
+                match quote_char {
+                    Some(q) if q == c => quote_char = None,
+                    Some(_) => {}
+                    None => quote_char = Some(c),
+                }
+                part.push(c);
+            }
+            '{' => {
+                curly_braces += 1;
+                part.push(c);
+            }
+            '}' => {
+                curly_braces -= 1;
+                part.push(c);
+            }
+            '|' if curly_braces == 0 && links == 0 && quote_char.is_none() => {
+                parts.push(part.to_owned());
+                part.clear();
+            }
+            _ => part.push(c),
+        }
+        pos += c.len_utf8();
+    }
+    parts.push(part);
+    if let Some(q) = quote_char {
+        return Err(format!("Unclosed quote: {}", q));
+    }
+    Ok(parts)
+}
+
+/// Position after the closing braces of a template, with `text` starting inside the template
+pub fn find_template_end(text: &str) -> Option<usize> {
+    let mut curly_braces_open: usize = 2;
+    let mut pos = 0;
+    while pos < text.len() {
+        if let Some(special) = special_at(text, pos) {
+            pos = special.ok()?.0;
+            continue;
+        }
+        let c = text[pos..].chars().next()?;
+        match c {
+            '{' => curly_braces_open += 1,
+            '}' => curly_braces_open -= 1,
+            _ => {}
+        }
+        pos += c.len_utf8();
+        if curly_braces_open == 0 {
+            return Some(pos);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_simple() {
+        let parts = split_template_params("|sparql=SELECT ?item {}|columns=label,P31").unwrap();
+        assert_eq!(
+            parts,
+            vec!["", "sparql=SELECT ?item {}", "columns=label,P31"]
+        );
+    }
+
+    #[test]
+    fn test_split_sparql_braces_and_paths() {
+        let parts = split_template_params(
+            "|sparql=SELECT ?item { ?item wdt:P31|wdt:P279 wd:Q5 }|sort=label",
+        )
+        .unwrap();
+        assert_eq!(parts.len(), 3);
+        assert_eq!(
+            parts[1],
+            "sparql=SELECT ?item { ?item wdt:P31|wdt:P279 wd:Q5 }"
+        );
+    }
+
+    #[test]
+    fn test_split_comments() {
+        let parts = split_template_params("|columns=label<!--,P31|P17-->,P18|sort=label").unwrap();
+        assert_eq!(parts, vec!["", "columns=label,P18", "sort=label"]);
+    }
+
+    #[test]
+    fn test_split_comment_with_quote() {
+        let parts = split_template_params("|columns=label<!-- don't -->|sort=label").unwrap();
+        assert_eq!(parts, vec!["", "columns=label", "sort=label"]);
+    }
+
+    #[test]
+    fn test_split_unclosed_comment() {
+        assert!(split_template_params("|columns=label<!--|sort=label").is_err());
+    }
+
+    #[test]
+    fn test_split_nowiki() {
+        let parts = split_template_params(
+            "|sparql=<nowiki>SELECT ?x { FILTER(REGEX(?l,\"a|b\")) }</nowiki>|sort=label",
+        )
+        .unwrap();
+        assert_eq!(parts[1], "sparql=SELECT ?x { FILTER(REGEX(?l,\"a|b\")) }");
+        assert_eq!(parts[2], "sort=label");
+        let parts = split_template_params("|summary=a<nowiki/>|sort=label").unwrap();
+        assert_eq!(parts, vec!["", "summary=a", "sort=label"]);
+    }
+
+    #[test]
+    fn test_split_links() {
+        let parts =
+            split_template_params("|header_template=[[Foo's page|Foo]]|sort=label").unwrap();
+        assert_eq!(
+            parts,
+            vec!["", "header_template=[[Foo's page|Foo]]", "sort=label"]
+        );
+    }
+
+    #[test]
+    fn test_split_nested_templates_and_parser_functions() {
+        let parts = split_template_params(
+            "|sparql=SELECT ?item { ?item wdt:P131 wd:{{#if:{{{1|}}}|{{{1}}}|Q64}} }|columns=label",
+        )
+        .unwrap();
+        assert_eq!(parts.len(), 3);
+        assert_eq!(
+            parts[1],
+            "sparql=SELECT ?item { ?item wdt:P131 wd:{{#if:{{{1|}}}|{{{1}}}|Q64}} }"
+        );
+    }
+
+    #[test]
+    fn test_split_quotes() {
+        let parts =
+            split_template_params("|sparql=SELECT ?x { BIND(\"a|b\" AS ?x) }|sort=label").unwrap();
+        assert_eq!(parts.len(), 3);
+        assert!(split_template_params("|sparql=\"unclosed|sort=label").is_err());
+    }
+
+    #[test]
+    fn test_find_template_end() {
+        let text = "|sparql=SELECT ?item { ?item wdt:P31 wd:Q5 }|columns=label}}\nrest";
+        assert_eq!(find_template_end(text), Some(text.find("\nrest").unwrap()));
+    }
+
+    #[test]
+    fn test_find_template_end_with_comment_and_nowiki() {
+        let text = "|sparql=<!-- }} -->SELECT<nowiki>}}</nowiki> ?item {}|columns={{{1}}}}}rest";
+        assert_eq!(find_template_end(text), Some(text.find("rest").unwrap()));
+    }
+
+    #[test]
+    fn test_find_template_end_unclosed() {
+        assert_eq!(find_template_end("|sparql=SELECT ?item {}"), None);
+        assert_eq!(find_template_end("|sparql=<!-- }}"), None);
+    }
+}
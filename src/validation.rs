@@ -0,0 +1,522 @@
+use crate::column::{Column, ColumnType};
+use crate::listeria_page::ListeriaPage;
+use crate::{PageElement, ReferencesParameter, SectionType, SortMode, Template};
+use regex::Regex;
+use std::fmt;
+
+/// A template parameter value that Listeria does not understand, and will ignore or replace with a default
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidationWarning {
+    pub parameter: String,
+    pub token: String,
+    pub message: String,
+}
+
+impl ValidationWarning {
+    fn new(parameter: &str, token: &str, message: &str) -> Self {
+        Self {
+            parameter: parameter.to_string(),
+            token: token.trim().to_string(),
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ValidationWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: '{}' {}", self.parameter, self.token, self.message)
+    }
+}
+
+/// Checks the list template parameters, in the same way they will be parsed
+pub fn validate_template(template: &Template) -> Vec<ValidationWarning> {
+    let mut ret = vec![];
+    let params = &template.params;
+
+    if let Some(columns) = params.get("columns") {
+        for part in columns.split(',') {
+            if part.trim().is_empty() {
+                ret.push(ValidationWarning::new("columns", part, "is an empty column"));
+                continue;
+            }
+            if Column::new(part).obj == ColumnType::Unknown {
+                ret.push(ValidationWarning::new(
+                    "columns",
+                    part,
+                    "is not a known column type",
+                ));
+            }
+        }
+    }
+
+    if let Some(sort) = non_empty(params.get("sort")) {
+        match SortMode::new(Some(sort)) {
+            SortMode::None => ret.push(ValidationWarning::new("sort", sort, "is not a valid sort mode")),
+            SortMode::SparqlVariable(_) if !sort.trim().starts_with('?') => ret.push(
+                ValidationWarning::new(
+                    "sort",
+                    sort,
+                    "is not a valid sort mode; use label, family_name, a property, or ?variable",
+                ),
+            ),
+            _ => {}
+        }
+    }
+
+    if let Some(section) = non_empty(params.get("section")) {
+        if let SectionType::None = SectionType::new_from_string_option(Some(section)) {
+            ret.push(ValidationWarning::new(
+                "section",
+                section,
+                "is not a property or @variable",
+            ));
+        }
+    }
+
+    if let Some(links) = non_empty(params.get("links")) {
+        let known = ["ALL", "LOCAL", "RED", "RED_ONLY", "TEXT", "REASONATOR"];
+        if !known.contains(&links.trim().to_uppercase().as_str()) {
+            ret.push(ValidationWarning::new(
+                "links",
+                links,
+                "is not a known link mode; using 'all'",
+            ));
+        }
+    }
+
+    if let Some(references) = non_empty(params.get("references")) {
+        let is_none = matches!(references.trim().to_uppercase().as_str(), "NONE" | "NO");
+        if !is_none && ReferencesParameter::new(Some(references)) == ReferencesParameter::None {
+            ret.push(ValidationWarning::new(
+                "references",
+                references,
+                "is not a known references mode (all, first, nowikipedia, withurl, or a list of properties); no references will be shown",
+            ));
+        }
+    }
+
+    if let Some(thumb) = non_empty(params.get("thumb")) {
+        if thumb.trim().parse::<u64>().is_err() {
+            ret.push(ValidationWarning::new(
+                "thumb",
+                thumb,
+                "is not a number; using the default thumbnail size",
+            ));
+        }
+    }
+
+    if let Some(paginate) = non_empty(params.get("paginate")) {
+        if !matches!(paginate.trim().parse::<usize>(), Ok(i) if i > 0) {
+            ret.push(ValidationWarning::new(
+                "paginate",
+                paginate,
+                "is not a positive number; the list will not be paginated",
+            ));
+        }
+    }
+
+    if let Some(min_section) = non_empty(params.get("min_section")) {
+        if min_section.trim().parse::<u64>().is_err() {
+            ret.push(ValidationWarning::new(
+                "min_section",
+                min_section,
+                "is not a number; using 2",
+            ));
+        }
+    }
+
+    ret
+}
+
+fn non_empty(s: Option<&String>) -> Option<&String> {
+    s.filter(|s| !s.trim().is_empty())
+}
+
+/// Template parameters that Listeria (or the original PHP version, for `freq`) understands
+const KNOWN_PARAMETERS: &[&str] = &[
+    "sparql",
+    "columns",
+    "sort",
+    "sort_order",
+    "section",
+    "min_section",
+    "links",
+    "language",
+    "thumb",
+    "row_template",
+    "row_template_raw",
+    "row_template_vars",
+    "header_template",
+    "skip_table",
+    "wdedit",
+    "references",
+    "autolist",
+    "autodesc",
+    "summary",
+    "one_row_per_item",
+    "wikibase",
+    "cache",
+    "on_error",
+    "warnings",
+    "paginate",
+    "tabbed_data",
+    "freq",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LintSeverity {
+    Error,   // The list will fail, or not be found at all
+    Warning, // The list will work, but maybe not as intended
+}
+
+/// A problem with a list definition, found without running the query
+#[derive(Debug, Clone, PartialEq)]
+pub struct LintIssue {
+    pub list: usize, // 1-based; 0 for the page itself
+    pub severity: LintSeverity,
+    pub message: String,
+}
+
+impl LintIssue {
+    fn new(list: usize, severity: LintSeverity, message: &str) -> Self {
+        Self {
+            list,
+            severity,
+            message: message.to_string(),
+        }
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == LintSeverity::Error
+    }
+}
+
+impl fmt::Display for LintIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            LintSeverity::Error => "ERROR",
+            LintSeverity::Warning => "WARNING",
+        };
+        match self.list {
+            0 => write!(f, "{}: {}", severity, self.message),
+            list => write!(f, "List {}: {}: {}", list, severity, self.message),
+        }
+    }
+}
+
+/// Checks all list definitions in the wikitext of a page, without running SPARQL or loading entities
+pub fn lint_wikitext(text: &str, page: &ListeriaPage) -> Result<Vec<LintIssue>, String> {
+    let (seperator_start, seperator_end) = PageElement::template_patterns(page)
+        .ok_or("No start/end templates configured for this wiki")?;
+    Ok(lint_text(text, &seperator_start, &seperator_end))
+}
+
+fn lint_text(text: &str, seperator_start: &Regex, seperator_end: &Regex) -> Vec<LintIssue> {
+    let mut ret = vec![];
+    let mut list = 0;
+    let mut pos = 0;
+    while let Some(match_start) = seperator_start.find_at(text, pos) {
+        // The start pattern also matches end templates
+        if let Some(m) = seperator_end.find_at(text, match_start.start()) {
+            if m.start() == match_start.start() {
+                ret.push(LintIssue::new(
+                    list,
+                    LintSeverity::Warning,
+                    "End template without a start template",
+                ));
+                pos = m.end();
+                continue;
+            }
+        }
+
+        list += 1;
+        let template_end =
+            match PageElement::get_template_end(text[match_start.end()..].to_string()) {
+                Some(p) => p + match_start.end(),
+                None => {
+                    ret.push(LintIssue::new(
+                        list,
+                        LintSeverity::Error,
+                        "Unbalanced braces; the start template is never closed",
+                    ));
+                    break;
+                }
+            };
+
+        let params = text[match_start.end()..template_end - 2].to_string();
+        match Template::new_from_params(String::new(), params) {
+            Ok(template) => lint_template(list, &template, &mut ret),
+            Err(e) => ret.push(LintIssue::new(
+                list,
+                LintSeverity::Error,
+                &format!("Template could not be parsed: {}", e),
+            )),
+        }
+
+        let next_start = seperator_start.find_at(text, template_end);
+        pos = match seperator_end.find_at(text, template_end) {
+            Some(m) => match next_start {
+                Some(next_start) if next_start.start() < m.start() => {
+                    ret.push(LintIssue::new(
+                        list,
+                        LintSeverity::Error,
+                        "No end template before the next list",
+                    ));
+                    next_start.start()
+                }
+                _ => m.end(),
+            },
+            None => {
+                ret.push(LintIssue::new(
+                    list,
+                    LintSeverity::Warning,
+                    "No end template; one will be added after the list on the next run",
+                ));
+                template_end
+            }
+        };
+    }
+    if list == 0 {
+        ret.push(LintIssue::new(
+            0,
+            LintSeverity::Warning,
+            "No list template found on this page",
+        ));
+    }
+    ret
+}
+
+fn lint_template(list: usize, template: &Template, issues: &mut Vec<LintIssue>) {
+    let mut keys: Vec<&String> = template.params.keys().collect();
+    keys.sort();
+    for key in keys {
+        if !KNOWN_PARAMETERS.contains(&key.trim().to_lowercase().as_str()) {
+            issues.push(LintIssue::new(
+                list,
+                LintSeverity::Warning,
+                &format!("Unknown parameter '{}'", key.trim()),
+            ));
+        }
+    }
+    match template.params.get("sparql") {
+        Some(sparql) if !sparql.trim().is_empty() => {}
+        _ => issues.push(LintIssue::new(
+            list,
+            LintSeverity::Error,
+            "Missing sparql parameter",
+        )),
+    }
+    for warning in validate_template(template) {
+        issues.push(LintIssue::new(
+            list,
+            LintSeverity::Warning,
+            &warning.to_string(),
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPARQL: &str = "SELECT ?item { ?item wdt:P31 wd:Q5 }";
+
+    fn lint(text: &str) -> Vec<LintIssue> {
+        let (seperator_start, seperator_end) =
+            PageElement::template_patterns_for("Liste Wikidata", "Fin de la liste Wikidata").unwrap();
+        lint_text(text, &seperator_start, &seperator_end)
+    }
+
+    fn template(params: &str) -> Template {
+        Template::new_from_params(String::new(), params.to_string()).unwrap()
+    }
+
+    /// The parameters and tokens that were warned about
+    fn warnings(params: &str) -> Vec<(String, String)> {
+        validate_template(&template(params))
+            .into_iter()
+            .map(|w| (w.parameter, w.token))
+            .collect()
+    }
+
+    fn warning(parameter: &str, token: &str) -> Vec<(String, String)> {
+        vec![(parameter.to_string(), token.to_string())]
+    }
+
+    #[test]
+    fn test_validate_template_valid() {
+        let params = "|sparql=SELECT ?item { ?item wdt:P31 wd:Q5 }\
+            |columns=number,label,description,item,qid,label/de,alias/fr,P31,P580/P582,P39/Q1/P580,?var,p18:Image\
+            |sort=P569|section=P17|links=red_only|references=P569,P570|thumb=120|paginate=100|min_section=3";
+        assert_eq!(warnings(params), vec![]);
+        assert_eq!(warnings("|sort=label|section=@region|links=ALL|references=all"), vec![]);
+        assert_eq!(warnings("|sort=?name|section=17|references=none"), vec![]);
+        assert_eq!(warnings("|sort=family_name|references=nowikipedia"), vec![]);
+        // Empty values mean the default
+        assert_eq!(warnings("|sort=|section=|links=|references=|thumb=|paginate=|min_section="), vec![]);
+    }
+
+    #[test]
+    fn test_validate_template_columns() {
+        assert_eq!(warnings("|columns=label,P31x"), warning("columns", "P31x"));
+        assert_eq!(warnings("|columns=lable,P31"), warning("columns", "lable"));
+        assert_eq!(warnings("|columns=label,,P31"), warning("columns", ""));
+    }
+
+    #[test]
+    fn test_validate_template_sort_and_section() {
+        assert_eq!(warnings("|sort=birthdate"), warning("sort", "birthdate"));
+        assert_eq!(warnings("|sort=date of birth"), warning("sort", "date of birth"));
+        assert_eq!(warnings("|section=country"), warning("section", "country"));
+        assert_eq!(warnings("|section=Q30"), warning("section", "Q30"));
+    }
+
+    #[test]
+    fn test_validate_template_links_and_references() {
+        assert_eq!(warnings("|links=blue"), warning("links", "blue"));
+        assert_eq!(warnings("|references=yes"), warning("references", "yes"));
+        assert_eq!(warnings("|references=P569,date"), warning("references", "P569,date"));
+    }
+
+    #[test]
+    fn test_validate_template_numbers() {
+        assert_eq!(warnings("|thumb=big"), warning("thumb", "big"));
+        assert_eq!(warnings("|thumb=120px"), warning("thumb", "120px"));
+        assert_eq!(warnings("|paginate=many"), warning("paginate", "many"));
+        assert_eq!(warnings("|paginate=0"), warning("paginate", "0"));
+        assert_eq!(warnings("|min_section=two"), warning("min_section", "two"));
+        assert_eq!(
+            warnings("|thumb=x|paginate=y|min_section=z"),
+            vec![
+                ("thumb".to_string(), "x".to_string()),
+                ("paginate".to_string(), "y".to_string()),
+                ("min_section".to_string(), "z".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lint_valid() {
+        let text = format!(
+            "Intro\n{{{{Wikidata list|sparql={}|columns=label,P31}}}}\n{{{{Wikidata list end}}}}\nOutro",
+            SPARQL
+        );
+        assert_eq!(lint(&text), vec![]);
+        let text = format!(
+            "{{{{Liste Wikidata|sparql={}}}}}\n{{{{Fin de la liste Wikidata}}}}\n\
+             {{{{Wikidata list|sparql={}}}}}\n{{{{Wikidata list end}}}}",
+            SPARQL, SPARQL
+        );
+        assert_eq!(lint(&text), vec![]);
+    }
+
+    #[test]
+    fn test_lint_missing_end_template() {
+        let text = format!("{{{{Wikidata list|sparql={}}}}}\nRest of the page", SPARQL);
+        assert_eq!(
+            lint(&text),
+            vec![LintIssue::new(
+                1,
+                LintSeverity::Warning,
+                "No end template; one will be added after the list on the next run"
+            )]
+        );
+    }
+
+    #[test]
+    fn test_lint_end_template_without_start() {
+        let text = format!(
+            "{{{{Wikidata list end}}}}\n{{{{Wikidata list|sparql={}}}}}\n{{{{Wikidata list end}}}}",
+            SPARQL
+        );
+        assert_eq!(
+            lint(&text),
+            vec![LintIssue::new(
+                0,
+                LintSeverity::Warning,
+                "End template without a start template"
+            )]
+        );
+    }
+
+    #[test]
+    fn test_lint_unbalanced_braces() {
+        let text = format!("{{{{Wikidata list|sparql={}|columns=label\n{{{{Wikidata list end}}}}", SPARQL);
+        let issues = lint(&text);
+        assert_eq!(
+            issues,
+            vec![LintIssue::new(
+                1,
+                LintSeverity::Error,
+                "Unbalanced braces; the start template is never closed"
+            )]
+        );
+        assert!(issues[0].is_error());
+    }
+
+    #[test]
+    fn test_lint_unknown_parameter() {
+        let text = format!(
+            "{{{{Wikidata list|sparql={}|colums=label}}}}\n{{{{Wikidata list end}}}}",
+            SPARQL
+        );
+        assert_eq!(
+            lint(&text),
+            vec![LintIssue::new(
+                1,
+                LintSeverity::Warning,
+                "Unknown parameter 'colums'"
+            )]
+        );
+    }
+
+    #[test]
+    fn test_lint_missing_sparql() {
+        let missing = LintIssue::new(1, LintSeverity::Error, "Missing sparql parameter");
+        let text = "{{Wikidata list|columns=label}}\n{{Wikidata list end}}";
+        assert_eq!(lint(text), vec![missing.clone()]);
+        let text = "{{Wikidata list|sparql= |columns=label}}\n{{Wikidata list end}}";
+        assert_eq!(lint(text), vec![missing]);
+    }
+
+    #[test]
+    fn test_lint_no_end_template_between_lists() {
+        let text = format!(
+            "{{{{Wikidata list|sparql={}}}}}\n{{{{Wikidata list|sparql={}}}}}\n{{{{Wikidata list end}}}}",
+            SPARQL, SPARQL
+        );
+        assert_eq!(
+            lint(&text),
+            vec![LintIssue::new(
+                1,
+                LintSeverity::Error,
+                "No end template before the next list"
+            )]
+        );
+    }
+
+    #[test]
+    fn test_lint_no_list() {
+        assert_eq!(
+            lint("Just some text, and {{another template}}"),
+            vec![LintIssue::new(
+                0,
+                LintSeverity::Warning,
+                "No list template found on this page"
+            )]
+        );
+    }
+
+    #[test]
+    fn test_lint_includes_parameter_warnings() {
+        let text = format!(
+            "{{{{Wikidata list|sparql={}|columns=lable}}}}\n{{{{Wikidata list end}}}}",
+            SPARQL
+        );
+        let issues = lint(&text);
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].list, 1);
+        assert_eq!(issues[0].severity, LintSeverity::Warning);
+        assert!(issues[0].message.starts_with("columns: 'lable'"));
+    }
+}